@@ -0,0 +1,343 @@
+use near_lib::promises::{assert_self, is_promise_success};
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupSet;
+use near_sdk::json_types::U128;
+use near_sdk::serde_json::{self, json};
+use near_sdk::{
+    env, ext_contract, near_bindgen, AccountId, Balance, Promise, PromiseOrValue, PromiseResult,
+};
+
+#[global_allocator]
+static ALLOC: near_sdk::wee_alloc::WeeAlloc<'_> = near_sdk::wee_alloc::WeeAlloc::INIT;
+
+const CODE_KEY: &[u8; 4] = b"code";
+
+const NO_DEPOSIT: Balance = 0;
+
+/// NEP-141 requires exactly 1 yoctoNEAR attached to `ft_transfer`.
+const ONE_YOCTO: Balance = 1;
+
+/// This gas spent on the call & account creation, the rest goes to the `new` call.
+const CREATE_CALL_GAS: u64 = 5_000_000_000_000;
+
+/// Gas for passing control over the pool to it's creator.
+const SET_CONTROLLER_CALL_GAS: u64 = 10_000_000_000_000;
+
+/// Gas for the callback that records or releases the pool.
+const ON_CREATE_CALL_GAS: u64 = 10_000_000_000_000;
+
+/// Gas for calls to pools and tokens when collecting fees.
+const COLLECT_CALL_GAS: u64 = 20_000_000_000_000;
+
+/// Attached to the transfer of shares to blabs to cover storage of it's account on the pool.
+/// The pool refunds what is not used back to the factory.
+const SHARES_STORAGE_DEPOSIT: Balance = 100_000_000_000_000_000_000_000;
+
+/// Factory of balancer pools: deploys pools, keeps the registry of them and collects exit fees.
+#[near_bindgen]
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct BFactory {
+    /// Receives exit fees collected by the factory.
+    blabs: AccountId,
+    /// Pools created by this factory.
+    pools: LookupSet<AccountId>,
+}
+
+impl Default for BFactory {
+    fn default() -> Self {
+        env::panic(b"BFactory should be initialized before usage")
+    }
+}
+
+#[near_bindgen]
+impl BFactory {
+    #[init]
+    pub fn new(#[serializer(borsh)] blabs: AccountId, #[serializer(borsh)] code: Vec<u8>) -> Self {
+        assert!(!env::state_exists(), "The contract is already initialized");
+        env::storage_write(CODE_KEY, &code);
+        Self {
+            blabs,
+            pools: LookupSet::new(b"p".to_vec()),
+        }
+    }
+
+    /// Creates pool at `name.<factory>` account, the caller becomes it's controller.
+    /// Attached deposit is passed to the pool to cover it's storage.
+    #[payable]
+    pub fn new_bpool(&mut self, name: AccountId) -> Promise {
+        let pool_id = format!("{}.{}", name, env::current_account_id());
+        assert!(self.pools.insert(&pool_id), "ERR_POOL_EXISTS");
+        let code = env::storage_read(CODE_KEY).expect("Code must be present");
+        Promise::new(pool_id.clone())
+            .create_account()
+            .deploy_contract(code)
+            .function_call(
+                b"new".to_vec(),
+                b"{}".to_vec(),
+                env::attached_deposit(),
+                env::prepaid_gas() - CREATE_CALL_GAS - SET_CONTROLLER_CALL_GAS - ON_CREATE_CALL_GAS,
+            )
+            .function_call(
+                b"set_controller".to_vec(),
+                json!({ "controller": env::predecessor_account_id() })
+                    .to_string()
+                    .into_bytes(),
+                NO_DEPOSIT,
+                SET_CONTROLLER_CALL_GAS,
+            )
+            .then(ext_self::on_new_bpool(
+                pool_id,
+                env::predecessor_account_id(),
+                env::attached_deposit().into(),
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                ON_CREATE_CALL_GAS,
+            ))
+    }
+
+    /// Callback after pool creation. If it failed, removes the pool from the registry
+    /// and refunds the deposit to the creator.
+    pub fn on_new_bpool(
+        &mut self,
+        pool_id: AccountId,
+        creator_id: AccountId,
+        deposit: U128,
+    ) -> bool {
+        assert_self();
+        if is_promise_success() {
+            env::log(format!("New pool {} by {}", pool_id, creator_id).as_bytes());
+            return true;
+        }
+        self.pools.remove(&pool_id);
+        if deposit.0 > 0 {
+            Promise::new(creator_id).transfer(deposit.0);
+        }
+        false
+    }
+
+    /// Returns whether the pool was created by this factory.
+    pub fn is_bpool(&self, pool_id: AccountId) -> bool {
+        self.pools.contains(&pool_id)
+    }
+
+    pub fn get_blabs(&self) -> AccountId {
+        self.blabs.clone()
+    }
+
+    pub fn set_blabs(&mut self, blabs: AccountId) {
+        self.assert_blabs();
+        self.blabs = blabs;
+    }
+
+    /// Sends pool shares collected by the factory as exit fees of the pool to blabs.
+    pub fn collect(&mut self, pool_id: AccountId) -> Promise {
+        self.assert_blabs();
+        assert!(self.pools.contains(&pool_id), "ERR_NOT_BPOOL");
+        ext_bpool::get_balance(
+            env::current_account_id(),
+            &pool_id,
+            NO_DEPOSIT,
+            COLLECT_CALL_GAS,
+        )
+        .then(ext_self::on_collect(
+            pool_id,
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            3 * COLLECT_CALL_GAS,
+        ))
+    }
+
+    /// Callback with the factory's balance of pool shares, transfers all of them to blabs.
+    /// Resolves to the amount of shares received by blabs.
+    pub fn on_collect(&mut self, pool_id: AccountId) -> PromiseOrValue<U128> {
+        assert_self();
+        let collected = match env::promise_result(0) {
+            PromiseResult::Successful(value) => {
+                serde_json::from_slice::<U128>(&value).expect("ERR_WRONG_BALANCE")
+            }
+            _ => env::panic(b"ERR_BALANCE_QUERY_FAILED"),
+        };
+        if collected.0 == 0 {
+            return PromiseOrValue::Value(collected);
+        }
+        ext_bpool::transfer(
+            self.blabs.clone(),
+            collected,
+            &pool_id,
+            SHARES_STORAGE_DEPOSIT,
+            COLLECT_CALL_GAS,
+        )
+        .then(ext_self::on_collect_transfer(
+            pool_id,
+            collected,
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            COLLECT_CALL_GAS,
+        ))
+        .into()
+    }
+
+    /// Callback after sending collected shares to blabs. If it failed, shares stay with the factory
+    /// and can be collected again.
+    pub fn on_collect_transfer(&mut self, pool_id: AccountId, amount: U128) -> U128 {
+        assert_self();
+        if is_promise_success() {
+            return amount;
+        }
+        env::log(format!("Failed to collect {} shares of {}", amount.0, pool_id).as_bytes());
+        U128(0)
+    }
+
+    /// Sends `amount` of NEP-141 `token_id` collected as exit fees on `rebind` and `unbind` to blabs.
+    pub fn withdraw_token(&mut self, token_id: AccountId, amount: U128) -> Promise {
+        self.assert_blabs();
+        ext_fungible_token::ft_transfer(
+            self.blabs.clone(),
+            amount,
+            None,
+            &token_id,
+            ONE_YOCTO,
+            COLLECT_CALL_GAS,
+        )
+    }
+
+    pub fn upgrade(&self, #[serializer(borsh)] code: Vec<u8>) {
+        self.assert_blabs();
+        env::storage_write(CODE_KEY, &code);
+    }
+}
+
+impl BFactory {
+    fn assert_blabs(&self) {
+        assert_eq!(env::predecessor_account_id(), self.blabs, "ERR_NOT_BLABS");
+    }
+}
+
+#[ext_contract(ext_bpool)]
+pub trait ExtBPool {
+    fn get_balance(&self, owner_id: AccountId) -> U128;
+
+    fn transfer(&mut self, new_owner_id: AccountId, amount: U128);
+}
+
+#[ext_contract(ext_fungible_token)]
+pub trait ExtFungibleToken {
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
+}
+
+#[ext_contract(ext_self)]
+pub trait ExtSelf {
+    fn on_new_bpool(&mut self, pool_id: AccountId, creator_id: AccountId, deposit: U128) -> bool;
+
+    fn on_collect(&mut self, pool_id: AccountId) -> PromiseOrValue<U128>;
+
+    fn on_collect_transfer(&mut self, pool_id: AccountId, amount: U128) -> U128;
+}
+
+#[cfg(test)]
+mod tests {
+    use near_lib::context::{accounts, testing_env_with_promise_results, VMContextBuilder};
+    use near_sdk::{testing_env, MockedBlockchain};
+
+    use super::*;
+
+    #[test]
+    fn test_basics() {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(1))
+            .finish());
+        let mut factory = BFactory::new(accounts(1), vec![].into());
+        factory.new_bpool("pool".to_string());
+        assert!(factory.is_bpool(format!("pool.{}", accounts(0))));
+        assert!(!factory.is_bpool(accounts(2)));
+        factory.collect(format!("pool.{}", accounts(0)));
+        factory.set_blabs(accounts(2));
+        assert_eq!(factory.get_blabs(), accounts(2));
+    }
+
+    #[test]
+    fn test_on_collect() {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .finish());
+        let mut factory = BFactory::new(accounts(1), vec![].into());
+        testing_env_with_promise_results(
+            VMContextBuilder::new()
+                .current_account_id(accounts(0))
+                .predecessor_account_id(accounts(0))
+                .finish(),
+            PromiseResult::Successful(b"\"10\"".to_vec()),
+        );
+        assert!(matches!(
+            factory.on_collect(accounts(2)),
+            PromiseOrValue::Promise(_)
+        ));
+    }
+
+    #[test]
+    fn test_on_collect_nothing() {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .finish());
+        let mut factory = BFactory::new(accounts(1), vec![].into());
+        testing_env_with_promise_results(
+            VMContextBuilder::new()
+                .current_account_id(accounts(0))
+                .predecessor_account_id(accounts(0))
+                .finish(),
+            PromiseResult::Successful(b"\"0\"".to_vec()),
+        );
+        assert!(matches!(
+            factory.on_collect(accounts(2)),
+            PromiseOrValue::Value(U128(0))
+        ));
+    }
+
+    #[test]
+    fn test_on_collect_transfer() {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .finish());
+        let mut factory = BFactory::new(accounts(1), vec![].into());
+        testing_env_with_promise_results(
+            VMContextBuilder::new()
+                .current_account_id(accounts(0))
+                .predecessor_account_id(accounts(0))
+                .finish(),
+            PromiseResult::Successful(vec![]),
+        );
+        assert_eq!(factory.on_collect_transfer(accounts(2), U128(10)), U128(10));
+        testing_env_with_promise_results(
+            VMContextBuilder::new()
+                .current_account_id(accounts(0))
+                .predecessor_account_id(accounts(0))
+                .finish(),
+            PromiseResult::Failed,
+        );
+        assert_eq!(factory.on_collect_transfer(accounts(2), U128(10)), U128(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_POOL_EXISTS")]
+    fn test_duplicate_pool() {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .finish());
+        let mut factory = BFactory::new(accounts(1), vec![].into());
+        factory.new_bpool("pool".to_string());
+        factory.new_bpool("pool".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_BLABS")]
+    fn test_collect_not_blabs() {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(2))
+            .finish());
+        let mut factory = BFactory::new(accounts(1), vec![].into());
+        factory.new_bpool("pool".to_string());
+        factory.collect(format!("pool.{}", accounts(0)));
+    }
+}
@@ -24,16 +24,25 @@ pub const BPOW_PRECISION: Balance = BONE / 10_000_000_000;
 pub const MAX_IN_RATIO: Balance = BONE / 2;
 pub const MAX_OUT_RATIO: Balance = BONE / 3 + 1;
 
+/// Share of total supply that must vote for a new swap fee.
+pub const FEE_QUORUM: Balance = BONE / 4;
+/// Voting on a swap fee proposal lasts 3 days, in nanoseconds.
+pub const FEE_VOTING_PERIOD: u64 = 3 * 24 * 60 * 60 * 1_000_000_000;
+/// Passed swap fee can be applied 1 day after the voting ended.
+pub const FEE_TIMELOCK: u64 = 24 * 60 * 60 * 1_000_000_000;
+
 pub const NO_DEPOSIT: Balance = 0;
+/// NEP-141 requires exactly 1 yoctoNEAR attached to `ft_transfer`.
+pub const ONE_YOCTO: Balance = 1;
 
 pub mod gas {
     pub const BASE_GAS: u64 = 20_000_000_000_000;
 
-    pub const NEP21_TRANSFER: u64 = BASE_GAS;
+    pub const FT_TRANSFER: u64 = BASE_GAS;
 
-    pub const ON_PULL_CALLBACK: u64 = BASE_GAS;
+    pub const ON_PUSH_CALLBACK: u64 = BASE_GAS;
 
-    pub const NEP21_TRANSFER_FROM: u64 = BASE_GAS;
+    pub const FT_BALANCE_OF: u64 = BASE_GAS;
 
-    pub const ON_PUSH_CALLBACK: u64 = BASE_GAS;
+    pub const ON_GULP_CALLBACK: u64 = BASE_GAS;
 }
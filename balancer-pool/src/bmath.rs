@@ -1,6 +1,7 @@
 use near_sdk::Balance;
 
-use crate::bconst::{Weight, BONE};
+use crate::bconst::{Weight, BONE, EXIT_FEE};
+use crate::bnum::{badd, bdiv, bmul, bpow, bsub};
 
 /**********************************************************************************************
 // calcSpotPrice                                                                             //
@@ -12,15 +13,360 @@ use crate::bconst::{Weight, BONE};
 // sF = swapFee                                                                              //
 **********************************************************************************************/
 pub fn calc_spot_price(
-    balance_in: Balance,
-    weight_in: Weight,
-    balace_out: Balance,
-    weight_out: Weight,
+    token_balance_in: Balance,
+    token_weight_in: Weight,
+    token_balance_out: Balance,
+    token_weight_out: Weight,
     swap_fee: Balance,
 ) -> Balance {
-    let numer = balance_in / weight_in;
-    let denom = balace_out / weight_out;
-    let ratio = numer / denom;
-    let scale = BONE / (BONE - swap_fee);
-    ratio * scale
+    let numer = bdiv(token_balance_in, token_weight_in);
+    let denom = bdiv(token_balance_out, token_weight_out);
+    let ratio = bdiv(numer, denom);
+    let scale = bdiv(BONE, bsub(BONE, swap_fee));
+    bmul(ratio, scale)
+}
+
+/**********************************************************************************************
+// calcOutGivenIn                                                                            //
+// aO = tokenAmountOut                                                                       //
+// bO = tokenBalanceOut                                                                      //
+// bI = tokenBalanceIn              /      /            bI             \    (wI / wO) \      //
+// aI = tokenAmountIn    aO = bO * |  1 - | --------------------------  | ^            |     //
+// wI = tokenWeightIn               \      \ ( bI + ( aI * ( 1 - sF )) /              /      //
+// wO = tokenWeightOut                                                                       //
+// sF = swapFee                                                                              //
+**********************************************************************************************/
+pub fn calc_out_given_in(
+    token_balance_in: Balance,
+    token_weight_in: Weight,
+    token_balance_out: Balance,
+    token_weight_out: Weight,
+    token_amount_in: Balance,
+    swap_fee: Balance,
+) -> Balance {
+    let weight_ratio = bdiv(token_weight_in, token_weight_out);
+    let adjusted_in = bmul(token_amount_in, bsub(BONE, swap_fee));
+    let y = bdiv(token_balance_in, badd(token_balance_in, adjusted_in));
+    let foo = bpow(y, weight_ratio);
+    let bar = bsub(BONE, foo);
+    bmul(token_balance_out, bar)
+}
+
+/**********************************************************************************************
+// calcInGivenOut                                                                            //
+// aI = tokenAmountIn                                                                        //
+// bO = tokenBalanceOut               /  /     bO      \    (wO / wI)      \                 //
+// bI = tokenBalanceIn          bI * |  | ------------  | ^            - 1  |                //
+// aO = tokenAmountOut    aI =        \  \ ( bO - aO ) /                   /                 //
+// wI = tokenWeightIn           --------------------------------------------                 //
+// wO = tokenWeightOut                          ( 1 - sF )                                   //
+// sF = swapFee                                                                              //
+**********************************************************************************************/
+pub fn calc_in_given_out(
+    token_balance_in: Balance,
+    token_weight_in: Weight,
+    token_balance_out: Balance,
+    token_weight_out: Weight,
+    token_amount_out: Balance,
+    swap_fee: Balance,
+) -> Balance {
+    let weight_ratio = bdiv(token_weight_out, token_weight_in);
+    let diff = bsub(token_balance_out, token_amount_out);
+    let y = bdiv(token_balance_out, diff);
+    let foo = bsub(bpow(y, weight_ratio), BONE);
+    bdiv(bmul(token_balance_in, foo), bsub(BONE, swap_fee))
+}
+
+/**********************************************************************************************
+// calcPoolOutGivenSingleIn                                                                  //
+// pAo = poolAmountOut         /                                              \              //
+// tAi = tokenAmountIn        ///      /     //    wI \      \\       \     wI \             //
+// wI = tokenWeightIn        //| tAi *| 1 - || 1 - --  | * sF || + tBi \    --  \            //
+// tW = totalWeight     pAo=||  \      \     \\    tW /      //         | ^ tW   | * pS - pS //
+// tBi = tokenBalanceIn      \\  ------------------------------------- /        /            //
+// pS = poolSupply            \\                    tBi               /        /             //
+// sF = swapFee                \                                              /              //
+**********************************************************************************************/
+pub fn calc_pool_out_given_single_in(
+    token_balance_in: Balance,
+    token_weight_in: Weight,
+    pool_supply: Balance,
+    total_weight: Weight,
+    token_amount_in: Balance,
+    swap_fee: Balance,
+) -> Balance {
+    // Charge the trading fee for the proportion of tokenAi
+    // which is implicitly traded to the other pool tokens.
+    let normalized_weight = bdiv(token_weight_in, total_weight);
+    let zaz = bmul(bsub(BONE, normalized_weight), swap_fee);
+    let token_amount_in_after_fee = bmul(token_amount_in, bsub(BONE, zaz));
+    let new_token_balance_in = badd(token_balance_in, token_amount_in_after_fee);
+    let token_in_ratio = bdiv(new_token_balance_in, token_balance_in);
+    let pool_ratio = bpow(token_in_ratio, normalized_weight);
+    let new_pool_supply = bmul(pool_ratio, pool_supply);
+    bsub(new_pool_supply, pool_supply)
+}
+
+/**********************************************************************************************
+// calcSingleInGivenPoolOut                                                                  //
+// tAi = tokenAmountIn              //(pS + pAo)\     /    1    \\                           //
+// pS = poolSupply                 || ---------  | ^ | --------- || * bI - bI                //
+// pAo = poolAmountOut              \\    pS    /     \(wI / tW)//                           //
+// bI = balanceIn          tAi =  --------------------------------------------               //
+// wI = weightIn                              /      wI  \                                   //
+// tW = totalWeight                          |  1 - ----  |  * sF                            //
+// sF = swapFee                               \      tW  /                                   //
+**********************************************************************************************/
+pub fn calc_single_in_given_pool_out(
+    token_balance_in: Balance,
+    token_weight_in: Weight,
+    pool_supply: Balance,
+    total_weight: Weight,
+    pool_amount_out: Balance,
+    swap_fee: Balance,
+) -> Balance {
+    let normalized_weight = bdiv(token_weight_in, total_weight);
+    let new_pool_supply = badd(pool_supply, pool_amount_out);
+    let pool_ratio = bdiv(new_pool_supply, pool_supply);
+    let boo = bdiv(BONE, normalized_weight);
+    let token_in_ratio = bpow(pool_ratio, boo);
+    let new_token_balance_in = bmul(token_in_ratio, token_balance_in);
+    let token_amount_in_after_fee = bsub(new_token_balance_in, token_balance_in);
+    // Do reverse order of fees charged in joinswap_ExternAmountIn, this way
+    // ``` pAo == joinswap_ExternAmountIn(Ti, joinswap_PoolAmountOut(pAo, Ti)) ```
+    let zar = bmul(bsub(BONE, normalized_weight), swap_fee);
+    bdiv(token_amount_in_after_fee, bsub(BONE, zar))
+}
+
+/**********************************************************************************************
+// calcSingleOutGivenPoolIn                                                                  //
+// tAo = tokenAmountOut            /      /                                             \\   //
+// bO = tokenBalanceOut           /      // pS - (pAi * (1 - eF)) \     /    1    \      \\  //
+// pAi = poolAmountIn            | bO - || ----------------------- | ^ | --------- | * b0 || //
+// ps = poolSupply                \      \\          pS           /     \(wO / tW)/      //  //
+// wI = tokenWeightIn      tAo =   \      \                                             //   //
+// tW = totalWeight                    /     /      wO \       \                             //
+// sF = swapFee                    *  | 1 - |  1 - ---- | * sF  |                            //
+// eF = exitFee                        \     \      tW /       /                             //
+**********************************************************************************************/
+pub fn calc_single_out_given_pool_in(
+    token_balance_out: Balance,
+    token_weight_out: Weight,
+    pool_supply: Balance,
+    total_weight: Weight,
+    pool_amount_in: Balance,
+    swap_fee: Balance,
+) -> Balance {
+    let normalized_weight = bdiv(token_weight_out, total_weight);
+    // Charge exit fee on the pool token side
+    // pAiAfterExitFee = pAi*(1-exitFee)
+    let pool_amount_in_after_exit_fee = bmul(pool_amount_in, bsub(BONE, EXIT_FEE));
+    let new_pool_supply = bsub(pool_supply, pool_amount_in_after_exit_fee);
+    let pool_ratio = bdiv(new_pool_supply, pool_supply);
+    // newBalTo = poolRatio^(1/weightTo) * balTo;
+    let token_out_ratio = bpow(pool_ratio, bdiv(BONE, normalized_weight));
+    let new_token_balance_out = bmul(token_out_ratio, token_balance_out);
+    let token_amount_out_before_swap_fee = bsub(token_balance_out, new_token_balance_out);
+    // Charge swap fee on the output token side
+    // tAo = tAoBeforeSwapFee * (1 - (1-weightTo) * swapFee)
+    let zaz = bmul(bsub(BONE, normalized_weight), swap_fee);
+    bmul(token_amount_out_before_swap_fee, bsub(BONE, zaz))
+}
+
+/**********************************************************************************************
+// calcPoolInGivenSingleOut                                                                  //
+// pAi = poolAmountIn               // /               tAo             \\     / wO \     \   //
+// bO = tokenBalanceOut            // | bO - -------------------------- |\   | ---- |     \  //
+// tAo = tokenAmountOut      pS - ||   \     1 - ((1 - (tO / tW)) * sF)/  | ^ \ tW /  * pS | //
+// ps = poolSupply                 \\ -----------------------------------/                /  //
+// wO = tokenWeightOut  pAi =       \\               bO                 /                /   //
+// tW = totalWeight           -------------------------------------------------------------  //
+// sF = swapFee                                        ( 1 - eF )                            //
+// eF = exitFee                                                                              //
+**********************************************************************************************/
+pub fn calc_pool_in_given_single_out(
+    token_balance_out: Balance,
+    token_weight_out: Weight,
+    pool_supply: Balance,
+    total_weight: Weight,
+    token_amount_out: Balance,
+    swap_fee: Balance,
+) -> Balance {
+    // charge swap fee on the output token side
+    let normalized_weight = bdiv(token_weight_out, total_weight);
+    // tAoBeforeSwapFee = tAo / (1 - (1-weightTo) * swapFee) ;
+    let zoo = bsub(BONE, normalized_weight);
+    let zar = bmul(zoo, swap_fee);
+    let token_amount_out_before_swap_fee = bdiv(token_amount_out, bsub(BONE, zar));
+    let new_token_balance_out = bsub(token_balance_out, token_amount_out_before_swap_fee);
+    let token_out_ratio = bdiv(new_token_balance_out, token_balance_out);
+    // newPoolSupply = (ratioTo ^ weightTo) * poolSupply;
+    let pool_ratio = bpow(token_out_ratio, normalized_weight);
+    let new_pool_supply = bmul(pool_ratio, pool_supply);
+    let pool_amount_in_after_exit_fee = bsub(pool_supply, new_pool_supply);
+    // charge exit fee on the pool token side
+    // pAi = pAiAfterExitFee/(1-exitFee)
+    bdiv(pool_amount_in_after_exit_fee, bsub(BONE, EXIT_FEE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bnum::tests::assert_approx;
+
+    // Pool of 50 tokens with weight 5 against 20000 tokens with weight 20, 0.3% swap fee,
+    // 100 pool shares. Expected values are computed with arbitrary precision.
+    const BALANCE_IN: Balance = 50 * BONE;
+    const WEIGHT_IN: Weight = 5 * BONE;
+    const BALANCE_OUT: Balance = 20_000 * BONE;
+    const WEIGHT_OUT: Weight = 20 * BONE;
+    const TOTAL_WEIGHT: Weight = 25 * BONE;
+    const POOL_SUPPLY: Balance = 100 * BONE;
+    const SWAP_FEE: Balance = 3 * BONE / 1000;
+
+    #[test]
+    fn test_calc_spot_price() {
+        assert_approx(
+            calc_spot_price(BALANCE_IN, WEIGHT_IN, BALANCE_OUT, WEIGHT_OUT, SWAP_FEE),
+            0.010_030_090_270_812_437,
+        );
+        assert_eq!(
+            calc_spot_price(BALANCE_IN, WEIGHT_IN, BALANCE_OUT, WEIGHT_OUT, 0),
+            BONE / 100
+        );
+    }
+
+    #[test]
+    fn test_calc_out_given_in() {
+        assert_approx(
+            calc_out_given_in(
+                BALANCE_IN,
+                WEIGHT_IN,
+                BALANCE_OUT,
+                WEIGHT_OUT,
+                5 * BONE / 2,
+                SWAP_FEE,
+            ),
+            241.763_362_424_133_86,
+        );
+    }
+
+    #[test]
+    fn test_calc_in_given_out() {
+        assert_approx(
+            calc_in_given_out(
+                BALANCE_IN,
+                WEIGHT_IN,
+                BALANCE_OUT,
+                WEIGHT_OUT,
+                500 * BONE,
+                SWAP_FEE,
+            ),
+            5.344_871_615_660_922,
+        );
+    }
+
+    #[test]
+    fn test_calc_pool_out_given_single_in() {
+        assert_approx(
+            calc_pool_out_given_single_in(
+                BALANCE_IN,
+                WEIGHT_IN,
+                POOL_SUPPLY,
+                TOTAL_WEIGHT,
+                5 * BONE,
+                SWAP_FEE,
+            ),
+            1.920_039_646_932_928_6,
+        );
+    }
+
+    #[test]
+    fn test_calc_single_in_given_pool_out() {
+        assert_approx(
+            calc_single_in_given_pool_out(
+                BALANCE_IN,
+                WEIGHT_IN,
+                POOL_SUPPLY,
+                TOTAL_WEIGHT,
+                BONE,
+                SWAP_FEE,
+            ),
+            2.556_638_437_249_398_6,
+        );
+    }
+
+    #[test]
+    fn test_calc_single_out_given_pool_in() {
+        assert_approx(
+            calc_single_out_given_pool_in(
+                BALANCE_IN,
+                WEIGHT_IN,
+                POOL_SUPPLY,
+                TOTAL_WEIGHT,
+                BONE,
+                SWAP_FEE,
+            ),
+            2.444_616_310_988,
+        );
+    }
+
+    #[test]
+    fn test_calc_pool_in_given_single_out() {
+        assert_approx(
+            calc_pool_in_given_single_out(
+                BALANCE_IN,
+                WEIGHT_IN,
+                POOL_SUPPLY,
+                TOTAL_WEIGHT,
+                2 * BONE,
+                SWAP_FEE,
+            ),
+            0.815_104_661_582_739_8,
+        );
+    }
+
+    /// Exact results for a pool of 4 WETH and 12 DAI with weight 10 each, 0.3% swap fee and
+    /// 100 pool shares. These are not Balancer's published vectors: they are outputs of a port
+    /// of `BNum.sol` and `BMath.sol` at `BONE` = 10^24 and pin the current rounding, so changes
+    /// in the order of operations show up here while the tests above still pass.
+    #[test]
+    fn test_exact_rounding_regression() {
+        let (weth, dai, weight) = (4 * BONE, 12 * BONE, 10 * BONE);
+        assert_eq!(
+            calc_spot_price(weth, weight, dai, weight, SWAP_FEE),
+            334_336_342_360_414_577_064_527
+        );
+        assert_eq!(
+            calc_out_given_in(weth, weight, dai, weight, BONE / 2, SWAP_FEE),
+            1_329_776_592_197_399_133_044_352
+        );
+        assert_eq!(
+            calc_in_given_out(weth, weight, dai, weight, BONE, SWAP_FEE),
+            364_730_555_302_270_447_706_756
+        );
+        assert_eq!(
+            calc_pool_out_given_single_in(weth, weight, POOL_SUPPLY, 2 * weight, BONE, SWAP_FEE),
+            11_786_627_108_830_371_542_095_200
+        );
+        assert_eq!(
+            calc_single_in_given_pool_out(weth, weight, POOL_SUPPLY, 2 * weight, BONE, SWAP_FEE),
+            80_520_781_171_757_636_454_682
+        );
+        assert_eq!(
+            calc_single_out_given_pool_in(weth, weight, POOL_SUPPLY, 2 * weight, BONE, SWAP_FEE),
+            79_480_600_000_000_000_000_000
+        );
+        assert_eq!(
+            calc_pool_in_given_single_out(
+                weth,
+                weight,
+                POOL_SUPPLY,
+                2 * weight,
+                BONE / 2,
+                SWAP_FEE
+            ),
+            6_468_603_220_849_784_196_647_400
+        );
+    }
 }
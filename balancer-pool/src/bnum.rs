@@ -0,0 +1,160 @@
+//! Fixed point arithmetic with `BONE` as one, port of Balancer's `BNum.sol`.
+//! Intermediate results are computed in 256 bits and rounded to the nearest.
+
+use near_sdk::Balance;
+use uint::construct_uint;
+
+use crate::bconst::{BONE, BPOW_PRECISION, MAX_BPOW_BASE, MIN_BPOW_BASE};
+
+construct_uint! {
+    /// 256-bit unsigned integer.
+    pub struct U256(4);
+}
+
+fn to_balance(value: U256) -> Balance {
+    assert!(value <= U256::from(Balance::MAX), "ERR_OVERFLOW");
+    value.as_u128()
+}
+
+pub fn btoi(a: Balance) -> Balance {
+    a / BONE
+}
+
+pub fn bfloor(a: Balance) -> Balance {
+    btoi(a) * BONE
+}
+
+pub fn badd(a: Balance, b: Balance) -> Balance {
+    a.checked_add(b).expect("ERR_ADD_OVERFLOW")
+}
+
+pub fn bsub(a: Balance, b: Balance) -> Balance {
+    let (c, negative) = bsub_sign(a, b);
+    assert!(!negative, "ERR_SUB_UNDERFLOW");
+    c
+}
+
+/// Returns absolute difference and whether it's negative.
+pub fn bsub_sign(a: Balance, b: Balance) -> (Balance, bool) {
+    if a >= b {
+        (a - b, false)
+    } else {
+        (b - a, true)
+    }
+}
+
+pub fn bmul(a: Balance, b: Balance) -> Balance {
+    to_balance((U256::from(a) * U256::from(b) + U256::from(BONE / 2)) / U256::from(BONE))
+}
+
+pub fn bdiv(a: Balance, b: Balance) -> Balance {
+    assert_ne!(b, 0, "ERR_DIV_ZERO");
+    to_balance((U256::from(a) * U256::from(BONE) + U256::from(b / 2)) / U256::from(b))
+}
+
+/// Raises `a` to the integer power `n`, where `n` is not in `BONE` units.
+pub fn bpowi(mut a: Balance, mut n: Balance) -> Balance {
+    let mut z = if n % 2 != 0 { a } else { BONE };
+    n /= 2;
+    while n != 0 {
+        a = bmul(a, a);
+        if n % 2 != 0 {
+            z = bmul(z, a);
+        }
+        n /= 2;
+    }
+    z
+}
+
+/// Computes `base ^ exp`: integer part of the exponent by squaring,
+/// fractional part via `bpow_approx`.
+pub fn bpow(base: Balance, exp: Balance) -> Balance {
+    assert!(base >= MIN_BPOW_BASE, "ERR_BPOW_BASE_TOO_LOW");
+    assert!(base <= MAX_BPOW_BASE, "ERR_BPOW_BASE_TOO_HIGH");
+    let whole = bfloor(exp);
+    let remain = bsub(exp, whole);
+    let whole_pow = bpowi(base, btoi(whole));
+    if remain == 0 {
+        return whole_pow;
+    }
+    let partial_result = bpow_approx(base, remain, BPOW_PRECISION);
+    bmul(whole_pow, partial_result)
+}
+
+/// Computes `base ^ exp` for `exp < 1` using binomial series until the term is less than `precision`.
+pub fn bpow_approx(base: Balance, exp: Balance, precision: Balance) -> Balance {
+    let a = exp;
+    let (x, xneg) = bsub_sign(base, BONE);
+    let mut term = BONE;
+    let mut sum = term;
+    let mut negative = false;
+    let mut i = 1;
+    while term >= precision {
+        let big_k = i * BONE;
+        let (c, cneg) = bsub_sign(a, bsub(big_k, BONE));
+        term = bmul(term, bmul(c, x));
+        term = bdiv(term, big_k);
+        if term == 0 {
+            break;
+        }
+        if xneg {
+            negative = !negative;
+        }
+        if cneg {
+            negative = !negative;
+        }
+        if negative {
+            sum = bsub(sum, term);
+        } else {
+            sum = badd(sum, term);
+        }
+        i += 1;
+    }
+    sum
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Checks that `actual` in `BONE` units is within relative error of 10^-8 of `expected`.
+    pub fn assert_approx(actual: Balance, expected: f64) {
+        let actual = actual as f64 / BONE as f64;
+        assert!(
+            ((actual - expected) / expected).abs() < 1e-8,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_bmul_bdiv() {
+        assert_eq!(bmul(3 * BONE / 2, 3 * BONE / 2), 9 * BONE / 4);
+        assert_eq!(bdiv(BONE, 3 * BONE), 333_333_333_333_333_333_333_333);
+        assert_eq!(bdiv(2 * BONE, 3 * BONE), 666_666_666_666_666_666_666_667);
+        assert_eq!(bmul(bdiv(7 * BONE, 2 * BONE), 2 * BONE), 7 * BONE);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_SUB_UNDERFLOW")]
+    fn test_bsub_underflow() {
+        bsub(BONE, 2 * BONE);
+    }
+
+    #[test]
+    fn test_bpow() {
+        assert_eq!(bpowi(2 * BONE, 10), 1024 * BONE);
+        assert_eq!(bpow(3 * BONE / 2, 3 * BONE), 27 * BONE / 8);
+        assert_approx(bpow(3 * BONE / 2, 5 * BONE / 2), 2.755_675_960_631_075);
+        assert_approx(bpow(BONE / 2, 3 * BONE / 10), 0.812_252_396_356_235_5);
+        assert_approx(bpow(BONE / 4, BONE / 2), 0.5);
+        assert_approx(bpow(19 * BONE / 10, 7 * BONE / 10), 1.567_211_178_364_18);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_BPOW_BASE_TOO_HIGH")]
+    fn test_bpow_base_too_high() {
+        bpow(2 * BONE, BONE / 2);
+    }
+}
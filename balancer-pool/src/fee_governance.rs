@@ -0,0 +1,165 @@
+//! Governance of the swap fee by share holders after the pool is finalized.
+//! Share holders propose a new fee and vote with their shares, that are locked in the pool
+//! until the voting ends, so the same shares can't vote twice. Proposal passes if votes for it
+//! reach `FEE_QUORUM` of the total supply and outweigh votes against it, and is applied
+//! with `execute_swap_fee` after `FEE_TIMELOCK`.
+
+use borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::{env, near_bindgen, AccountId, Balance};
+use serde::Serialize;
+
+use crate::bconst::*;
+use crate::bnum::{badd, bmul};
+use crate::*;
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct FeeProposal {
+    id: u64,
+    proposer: AccountId,
+    swap_fee: Balance,
+    voting_end: u64,
+    votes_for: Balance,
+    votes_against: Balance,
+}
+
+/// Shares locked by the account voting on the proposal `proposal_id`.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct FeeVote {
+    proposal_id: u64,
+    amount: Balance,
+}
+
+/// Current swap fee proposal as returned by `get_fee_proposal`, times are in nanoseconds.
+#[derive(Serialize)]
+pub struct FeeProposalInfo {
+    pub id: U64,
+    pub proposer: AccountId,
+    pub swap_fee: U128,
+    pub voting_end: U64,
+    pub votes_for: U128,
+    pub votes_against: U128,
+}
+
+impl FeeProposal {
+    fn is_voting(&self) -> bool {
+        env::block_timestamp() < self.voting_end
+    }
+
+    fn is_passed(&self, total_supply: Balance) -> bool {
+        self.votes_for >= bmul(total_supply, FEE_QUORUM) && self.votes_for > self.votes_against
+    }
+}
+
+#[near_bindgen]
+impl BPool {
+    /// Proposes new swap fee, the caller must hold pool shares.
+    /// Replaces the previous proposal if it's voting ended and it didn't pass.
+    pub fn propose_swap_fee(&mut self, swap_fee: U128) -> U64 {
+        let swap_fee: Balance = swap_fee.into();
+        assert!(self.finalized, "ERR_NOT_FINALIZED");
+        assert!(swap_fee >= MIN_FEE, "ERR_MIN_FEE");
+        assert!(swap_fee <= MAX_FEE, "ERR_MAX_FEE");
+        let proposer = env::predecessor_account_id();
+        assert!(
+            self.token.get_balance(proposer.clone()) > 0,
+            "ERR_NO_SHARES"
+        );
+        if let Some(proposal) = &self.fee_proposal {
+            assert!(
+                !proposal.is_voting() && !proposal.is_passed(self.token.get_total_supply()),
+                "ERR_PROPOSAL_IN_PROGRESS"
+            );
+        }
+        self.fee_proposal_count += 1;
+        self.fee_proposal = Some(FeeProposal {
+            id: self.fee_proposal_count,
+            proposer,
+            swap_fee,
+            voting_end: env::block_timestamp() + FEE_VOTING_PERIOD,
+            votes_for: 0,
+            votes_against: 0,
+        });
+        self.fee_proposal_count.into()
+    }
+
+    /// Votes on the current proposal with `amount` of caller's shares, that are locked
+    /// until the voting ends. Shares locked for the previous proposals are unlocked.
+    pub fn vote_swap_fee(&mut self, support: bool, amount: U128) {
+        let amount: Balance = amount.into();
+        assert!(amount > 0, "ERR_ZERO_VOTE");
+        let proposal = self.fee_proposal.as_mut().expect("ERR_NO_PROPOSAL");
+        assert!(proposal.is_voting(), "ERR_VOTING_ENDED");
+        if support {
+            proposal.votes_for = badd(proposal.votes_for, amount);
+        } else {
+            proposal.votes_against = badd(proposal.votes_against, amount);
+        }
+        let proposal_id = proposal.id;
+
+        let voter = env::predecessor_account_id();
+        if let Some(vote) = self.fee_votes.get(&voter) {
+            assert_ne!(vote.proposal_id, proposal_id, "ERR_ALREADY_VOTED");
+            self.push_pool_share(voter.clone(), vote.amount);
+        }
+        self.pull_pool_share(voter.clone(), amount);
+        self.fee_votes.insert(
+            &voter,
+            &FeeVote {
+                proposal_id,
+                amount,
+            },
+        );
+    }
+
+    /// Returns shares locked by the caller's vote once the voting on it ended.
+    pub fn unlock_shares(&mut self) -> U128 {
+        let voter = env::predecessor_account_id();
+        let vote = self.fee_votes.get(&voter).expect("ERR_NO_VOTE");
+        if let Some(proposal) = &self.fee_proposal {
+            assert!(
+                proposal.id != vote.proposal_id || !proposal.is_voting(),
+                "ERR_VOTING_IN_PROGRESS"
+            );
+        }
+        self.fee_votes.remove(&voter);
+        self.push_pool_share(voter, vote.amount);
+        vote.amount.into()
+    }
+
+    /// Applies the swap fee of the passed proposal after the timelock, can be called by anyone.
+    pub fn execute_swap_fee(&mut self) {
+        let proposal = self.fee_proposal.as_ref().expect("ERR_NO_PROPOSAL");
+        assert!(
+            env::block_timestamp() >= proposal.voting_end + FEE_TIMELOCK,
+            "ERR_TIMELOCK"
+        );
+        assert!(
+            proposal.is_passed(self.token.get_total_supply()),
+            "ERR_PROPOSAL_FAILED"
+        );
+        self.swap_fee = proposal.swap_fee;
+        env::log(format!("Swap fee is set to {}", self.swap_fee).as_bytes());
+        self.fee_proposal = None;
+    }
+
+    pub fn get_fee_proposal(&self) -> Option<FeeProposalInfo> {
+        self.fee_proposal.as_ref().map(|proposal| FeeProposalInfo {
+            id: proposal.id.into(),
+            proposer: proposal.proposer.clone(),
+            swap_fee: proposal.swap_fee.into(),
+            voting_end: proposal.voting_end.into(),
+            votes_for: proposal.votes_for.into(),
+            votes_against: proposal.votes_against.into(),
+        })
+    }
+
+    /// Returns shares locked by the account's vote.
+    pub fn get_locked_shares(&self, account_id: AccountId) -> U128 {
+        self.fee_votes
+            .get(&account_id)
+            .map(|vote| vote.amount)
+            .unwrap_or(0)
+            .into()
+    }
+}
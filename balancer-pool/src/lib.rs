@@ -1,14 +1,43 @@
+use std::collections::HashMap;
+
 use borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::collections::UnorderedMap;
 use near_sdk::json_types::U128;
-use near_sdk::{env, ext_contract, near_bindgen, AccountId, Balance, Promise};
+use near_sdk::{env, ext_contract, near_bindgen, AccountId, Balance, Promise, PromiseResult};
 
 mod bconst;
 mod bmath;
+mod bnum;
+mod fee_governance;
+mod smart_pool;
+mod token_receiver;
 
 use bconst::*;
-use bmath::calc_spot_price;
-use near_lib::token::{ext_nep21, FungibleToken, Token};
+use bmath::{
+    calc_in_given_out, calc_out_given_in, calc_pool_in_given_single_out,
+    calc_pool_out_given_single_in, calc_single_in_given_pool_out, calc_single_out_given_pool_in,
+    calc_spot_price,
+};
+use bnum::{badd, bdiv, bmul, bsub};
+use fee_governance::{FeeProposal, FeeVote};
+use near_lib::promises::{assert_self, is_promise_success};
+use near_lib::token::{FungibleToken, Token};
+use serde::Serialize;
+use smart_pool::WeightUpdate;
+
+/// State of the pool as returned by `get_pool_info`, weights and balances are in order of `tokens`.
+#[derive(Serialize)]
+pub struct PoolInfo {
+    pub tokens: Vec<AccountId>,
+    pub denorm_weights: Vec<U128>,
+    pub balances: Vec<U128>,
+    pub total_weight: U128,
+    pub swap_fee: U128,
+    pub total_supply: U128,
+    pub controller: AccountId,
+    pub finalized: bool,
+    pub public_swap: bool,
+}
 
 #[derive(BorshDeserialize, BorshSerialize)]
 pub struct Record {
@@ -30,6 +59,18 @@ pub struct BPool {
     tokens: Vec<AccountId>,
     total_weight: Weight,
     token: Token,
+    /// Tokens received for `join_pool`: account -> token -> amount.
+    deposits: UnorderedMap<AccountId, HashMap<AccountId, Balance>>,
+    /// Sum of `deposits` per token, these tokens are not absorbed by `gulp`.
+    deposit_totals: UnorderedMap<AccountId, Balance>,
+    /// Gradual weight update scheduled by the controller.
+    weight_update: Option<WeightUpdate>,
+    /// Swap fee proposal of share holders, see `fee_governance`.
+    fee_proposal: Option<FeeProposal>,
+    fee_proposal_count: u64,
+    fee_votes: UnorderedMap<AccountId, FeeVote>,
+    /// Weights of tokens approved by the controller via `bind`, waiting for their balance.
+    pending_binds: UnorderedMap<AccountId, Weight>,
 }
 
 impl Default for BPool {
@@ -52,72 +93,97 @@ impl BPool {
             tokens: Vec::new(),
             total_weight: 0,
             token: Token::new(env::signer_account_id(), 0u128),
+            deposits: UnorderedMap::new(b"d".to_vec()),
+            deposit_totals: UnorderedMap::new(b"t".to_vec()),
+            weight_update: None,
+            fee_proposal: None,
+            fee_proposal_count: 0,
+            fee_votes: UnorderedMap::new(b"v".to_vec()),
+            pending_binds: UnorderedMap::new(b"b".to_vec()),
         }
     }
 
     // Getters
 
-    pub fn isPublicSwap(&self) -> bool {
+    pub fn is_public_swap(&self) -> bool {
         self.public_swap
     }
 
-    pub fn isFinalized(&self) -> bool {
+    pub fn is_finalized(&self) -> bool {
         self.finalized
     }
 
-    pub fn isBound(&self, token: AccountId) -> bool {
+    pub fn is_bound(&self, token: AccountId) -> bool {
         self.records
             .get(&token)
             .map(|record| record.bound)
             .unwrap_or(false)
     }
 
-    pub fn getNumTokens(&self) -> u64 {
+    pub fn get_num_tokens(&self) -> u64 {
         self.tokens.len() as u64
     }
 
-    pub fn getCurrentTokens(&self) -> Vec<AccountId> {
+    pub fn get_current_tokens(&self) -> Vec<AccountId> {
         self.tokens.clone()
     }
 
-    pub fn getFinalTokens(&self) -> Vec<AccountId> {
+    pub fn get_final_tokens(&self) -> Vec<AccountId> {
         assert!(self.finalized, "ERR_NOT_FINALIZED");
         self.tokens.clone()
     }
 
-    pub fn getDenormalizedWeight(&self, token: AccountId) -> U128 {
-        assert!(self.isBound(token.clone()), "ERR_NOT_BOUND");
+    pub fn get_denormalized_weight(&self, token: AccountId) -> U128 {
+        assert!(self.is_bound(token.clone()), "ERR_NOT_BOUND");
         self.records.get(&token).unwrap().denorm.into()
     }
 
-    pub fn getTotalDenormalizedWeight(&self) -> U128 {
+    pub fn get_total_denormalized_weight(&self) -> U128 {
         self.total_weight.into()
     }
 
-    pub fn getNormalizedWeight(&self, token: AccountId) -> U128 {
-        assert!(self.isBound(token.clone()), "ERR_NOT_BOUND");
+    pub fn get_normalized_weight(&self, token: AccountId) -> U128 {
+        assert!(self.is_bound(token.clone()), "ERR_NOT_BOUND");
         let denorm = self.records.get(&token).unwrap().denorm;
-        // TODO: this division is special?
-        (denorm / self.total_weight).into()
+        bdiv(denorm, self.total_weight).into()
     }
 
-    pub fn getBalance(&self, token: AccountId) -> U128 {
-        assert!(self.isBound(token.clone()), "ERR_NOT_BOUND");
+    pub fn get_token_balance(&self, token: AccountId) -> U128 {
+        assert!(self.is_bound(token.clone()), "ERR_NOT_BOUND");
         self.records.get(&token).unwrap().balance.into()
     }
 
-    pub fn getSwapFee(&self) -> U128 {
+    pub fn get_swap_fee(&self) -> U128 {
         self.swap_fee.into()
     }
 
-    pub fn getController(&self) -> AccountId {
+    pub fn get_controller(&self) -> AccountId {
         self.controller.clone()
     }
 
+    pub fn get_pool_info(&self) -> PoolInfo {
+        let records: Vec<Record> = self
+            .tokens
+            .iter()
+            .map(|token| self.records.get(token).unwrap())
+            .collect();
+        PoolInfo {
+            tokens: self.tokens.clone(),
+            denorm_weights: records.iter().map(|r| r.denorm.into()).collect(),
+            balances: records.iter().map(|r| r.balance.into()).collect(),
+            total_weight: self.total_weight.into(),
+            swap_fee: self.swap_fee.into(),
+            total_supply: self.token.get_total_supply().into(),
+            controller: self.controller.clone(),
+            finalized: self.finalized,
+            public_swap: self.public_swap,
+        }
+    }
+
     // Setters.
 
-    pub fn setSwapFee(&mut self, swapFee: U128) {
-        let swap_fee = swapFee.into();
+    pub fn set_swap_fee(&mut self, swap_fee: U128) {
+        let swap_fee: Balance = swap_fee.into();
         assert!(!self.finalized, "ERR_IS_FINALIZED");
         assert_eq!(
             env::predecessor_account_id(),
@@ -125,11 +191,11 @@ impl BPool {
             "ERR_NOT_CONTROLLER"
         );
         assert!(swap_fee >= MIN_FEE, "ERR_MIN_FEE");
-        assert!(swap_fee <= MAX_FEE, "ERR_MIN_FEE");
+        assert!(swap_fee <= MAX_FEE, "ERR_MAX_FEE");
         self.swap_fee = swap_fee;
     }
 
-    pub fn setController(&mut self, controller: AccountId) {
+    pub fn set_controller(&mut self, controller: AccountId) {
         assert_eq!(
             env::predecessor_account_id(),
             self.controller,
@@ -138,7 +204,7 @@ impl BPool {
         self.controller = controller;
     }
 
-    pub fn setPublicSwap(&mut self, public: bool) {
+    pub fn set_public_swap(&mut self, public: bool) {
         assert!(!self.finalized, "ERR_IS_FINALIZED");
         assert_eq!(
             env::predecessor_account_id(),
@@ -164,85 +230,108 @@ impl BPool {
         self.push_pool_share(env::predecessor_account_id(), INIT_POOL_SUPPLY);
     }
 
-    pub fn bind(&mut self, token: AccountId, balance: U128, denorm: U128) {
+    /// Approves binding of the token with given weight. The token is bound once the controller
+    /// sends it's balance via `ft_transfer_call` with `"bind"` message.
+    pub fn bind(&mut self, token: AccountId, denorm: U128) {
+        let denorm: Weight = denorm.into();
         assert_eq!(
             env::predecessor_account_id(),
             self.controller,
             "ERR_NOT_CONTROLLER"
         );
-        assert!(!self.isBound(token.clone()), "ERR_IS_BOUND");
-        assert!(!self.finalized, "ERR_IS_FINALIZED");
-        assert!(self.tokens.len() < MAX_BOUND_TOKENS, "ERR_MAX_TOKENS");
+        self.assert_bind(&token);
+        assert!(denorm >= MIN_WEIGHT, "ERR_MIN_WEIGHT");
+        assert!(denorm <= MAX_WEIGHT, "ERR_MAX_WEIGHT");
+        self.pending_binds.insert(&token, &denorm);
+    }
 
-        self.records.insert(
-            &token,
-            &Record {
-                bound: true,
-                index: self.tokens.len() as u64,
-                denorm: 0,
-                balance: 0,
-            },
-        );
-        self.tokens.push(token.clone());
-        self.rebind(token, balance.into(), denorm.into());
+    /// Changes weight of the token and lowers it's balance, withdrawn tokens are sent to
+    /// the controller. Balances are increased by sending tokens via `ft_transfer_call`
+    /// with `rebind` message.
+    pub fn rebind(&mut self, token: AccountId, balance: U128, denorm: U128) {
+        let (balance, denorm): (Balance, Weight) = (balance.into(), denorm.into());
+        let mut record = self.assert_rebind(&env::predecessor_account_id(), &token, denorm);
+        assert!(balance >= MIN_BALANCE, "ERR_MIN_BALANCE");
+        assert!(balance <= record.balance, "ERR_SEND_TOKENS_TO_INCREASE");
+
+        // Tokens are sent out after updating the state, `on_push` restores it on failure.
+        let token_balance_withdrawn = record.balance - balance;
+        self.set_denorm(&mut record, denorm);
+        record.balance = balance;
+        self.records.insert(&token, &record);
+        let token_exit_fee = bmul(token_balance_withdrawn, EXIT_FEE);
+        if token_balance_withdrawn > token_exit_fee {
+            self.send_tokens(
+                &token,
+                &env::predecessor_account_id(),
+                token_balance_withdrawn - token_exit_fee,
+                0,
+            );
+        }
+        if token_exit_fee > 0 {
+            self.send_tokens(&token, &self.factory, token_exit_fee, 0);
+        }
     }
 
-    pub fn rebind(&mut self, token: AccountId, balance: Balance, denorm: Weight) {
+    /// Removes the token from the pool, it's balance minus exit fee is sent to the controller.
+    pub fn unbind(&mut self, token: AccountId) {
         assert_eq!(
             env::predecessor_account_id(),
             self.controller,
             "ERR_NOT_CONTROLLER"
         );
-        assert!(self.isBound(token.clone()), "ERR_NOT_BOUND");
+        assert!(self.is_bound(token.clone()), "ERR_NOT_BOUND");
         assert!(!self.finalized, "ERR_IS_FINALIZED");
+        assert!(
+            self.weight_update.is_none(),
+            "ERR_WEIGHT_UPDATE_IN_PROGRESS"
+        );
 
-        assert!(denorm >= MIN_WEIGHT, "ERR_MIN_WEIGHT");
-        assert!(denorm <= MAX_WEIGHT, "ERR_MAX_WEIGHT");
-        assert!(balance >= MIN_BALANCE, "ERR_MIN_BALANCE");
-
-        let mut record = self.records.get(&token).unwrap();
-        let old_weight = record.denorm;
-        record.denorm = denorm;
-        if denorm > old_weight {
-            self.total_weight = self.total_weight + (denorm - old_weight);
-        } else {
-            self.total_weight = self.total_weight - (old_weight - denorm);
-        }
-
-        let old_balance = record.balance;
-        record.balance = balance;
-        if balance > old_balance {
-            self.pull_underlying(
+        let token_balance = self.records.get(&token).unwrap().balance;
+        let token_exit_fee = bmul(token_balance, EXIT_FEE);
+        self.remove_token(&token);
+        if token_balance > token_exit_fee {
+            self.send_tokens(
                 &token,
                 &env::predecessor_account_id(),
-                balance - old_balance,
+                token_balance - token_exit_fee,
+                0,
             );
-        } else {
-            let token_balance_withdrawn = old_balance - balance;
-            let token_exit_fee = token_balance_withdrawn * EXIT_FEE;
-            self.push_underlying(
-                token.clone(),
-                env::predecessor_account_id(),
-                token_balance_withdrawn - token_exit_fee,
-            );
-            self.push_underlying(token.clone(), self.factory.clone(), token_exit_fee);
         }
-        // TODO: move this into the on_pull or else clause.
-        self.records.insert(&token, &record);
+        if token_exit_fee > 0 {
+            self.send_tokens(&token, &self.factory, token_exit_fee, 0);
+        }
     }
 
-    pub fn unbind(&mut self, token: AccountId) {}
-
-    /// Absorb any tokens that have been sent to this contract into the pool.
-    pub fn gulp(&mut self, token: AccountId) {
-        // TODO: call ext_nep21.balance(env::current_account_id(), token.clone()).then()
+    /// Absorbs tokens that were sent to the pool directly into the balance of the token.
+    /// Tokens of outgoing transfers that are still in flight are counted by the token
+    /// contract as well, so only the controller can call it.
+    pub fn gulp(&mut self, token: AccountId) -> Promise {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.controller,
+            "ERR_NOT_CONTROLLER"
+        );
+        assert!(self.is_bound(token.clone()), "ERR_NOT_BOUND");
+        ext_fungible_token::ft_balance_of(
+            env::current_account_id(),
+            &token,
+            NO_DEPOSIT,
+            gas::FT_BALANCE_OF,
+        )
+        .then(ext_self::on_gulp(
+            token,
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            gas::ON_GULP_CALLBACK,
+        ))
     }
 
-    pub fn getSpotPrice(&self, tokenIn: AccountId, tokenOut: AccountId) -> Balance {
-        assert!(self.isBound(tokenIn.clone()), "ERR_NOT_BOUND");
-        assert!(self.isBound(tokenOut.clone()), "ERR_NOT_BOUND");
-        let in_record = self.records.get(&tokenIn).unwrap();
-        let out_record = self.records.get(&tokenOut).unwrap();
+    pub fn get_spot_price(&self, token_in: AccountId, token_out: AccountId) -> U128 {
+        assert!(self.is_bound(token_in.clone()), "ERR_NOT_BOUND");
+        assert!(self.is_bound(token_out.clone()), "ERR_NOT_BOUND");
+        let in_record = self.records.get(&token_in).unwrap();
+        let out_record = self.records.get(&token_out).unwrap();
         calc_spot_price(
             in_record.balance,
             in_record.denorm,
@@ -250,13 +339,14 @@ impl BPool {
             out_record.denorm,
             self.swap_fee,
         )
+        .into()
     }
 
-    pub fn getSpotPriceSansFee(&self, tokenIn: AccountId, tokenOut: AccountId) -> Balance {
-        assert!(self.isBound(tokenIn.clone()), "ERR_NOT_BOUND");
-        assert!(self.isBound(tokenOut.clone()), "ERR_NOT_BOUND");
-        let in_record = self.records.get(&tokenIn).unwrap();
-        let out_record = self.records.get(&tokenOut).unwrap();
+    pub fn get_spot_price_sans_fee(&self, token_in: AccountId, token_out: AccountId) -> U128 {
+        assert!(self.is_bound(token_in.clone()), "ERR_NOT_BOUND");
+        assert!(self.is_bound(token_out.clone()), "ERR_NOT_BOUND");
+        let in_record = self.records.get(&token_in).unwrap();
+        let out_record = self.records.get(&token_out).unwrap();
         calc_spot_price(
             in_record.balance,
             in_record.denorm,
@@ -264,64 +354,303 @@ impl BPool {
             out_record.denorm,
             0,
         )
+        .into()
     }
 
-    pub fn joinPool(&mut self, poolAmountOut: Balance, maxAmountsIn: Vec<Balance>) {
+    /// Joins the pool for `pool_amount_out` shares, paying with tokens deposited
+    /// via `ft_transfer_call` with `deposit` message. Unused deposits stay with the caller.
+    pub fn join_pool(&mut self, pool_amount_out: U128, max_amounts_in: Vec<U128>) {
+        let pool_amount_out: Balance = pool_amount_out.into();
         assert!(self.finalized, "ERR_NOT_FINALIZED");
+        assert_eq!(
+            max_amounts_in.len(),
+            self.tokens.len(),
+            "ERR_AMOUNTS_MISMATCH"
+        );
         let pool_total = self.token.get_total_supply();
-        let ratio = poolAmountOut / pool_total;
-        assert_ne!(ratio, 0, "ERR_MAX_APPROX");
+        let ratio = bdiv(pool_amount_out, pool_total);
+        assert_ne!(ratio, 0, "ERR_MATH_APPROX");
 
-        for i in 0..self.tokens.len() {
-            let mut record = self.records.get(&self.tokens[i]).unwrap();
-            let token_amount_in = ratio * record.balance;
+        let sender_id = env::predecessor_account_id();
+        let mut deposits = self.deposits.get(&sender_id).unwrap_or_default();
+        for (i, token) in self.tokens.clone().iter().enumerate() {
+            let mut record = self.records.get(token).unwrap();
+            let token_amount_in = bmul(ratio, record.balance);
             assert_ne!(token_amount_in, 0, "ERR_MATH_APPROX");
-            assert!(token_amount_in <= maxAmountsIn[i], "ERR_LIMIT_IN");
-            record.balance += token_amount_in;
-            self.pull_underlying(
-                &self.tokens[i].clone(),
-                &env::predecessor_account_id(),
-                token_amount_in,
-            );
-            // TODO: join all promises and only save records / mint shares on success.
+            assert!(token_amount_in <= max_amounts_in[i].into(), "ERR_LIMIT_IN");
+            self.take_deposit(&mut deposits, token, token_amount_in);
+            record.balance = badd(record.balance, token_amount_in);
+            self.records.insert(token, &record);
         }
-        self.mint_pool_share(poolAmountOut);
-        self.push_pool_share(env::predecessor_account_id(), poolAmountOut);
+        self.save_deposits(&sender_id, &deposits);
+        self.mint_pool_share(pool_amount_out);
+        self.push_pool_share(sender_id, pool_amount_out);
     }
 
-    pub fn exitPool(&mut self, poolAmountIn: Balance, minAmountsOut: Vec<Balance>) {
+    /// Registers the caller to receive tokens via `ft_transfer_call` with `deposit` message.
+    /// Attached deposit pays for the storage of the caller's deposits, the rest is refunded.
+    /// Deposits hold at most one entry per bound token, so registration bounds their storage.
+    #[payable]
+    pub fn register_deposits(&mut self) {
+        let initial_storage = env::storage_usage();
+        let account_id = env::predecessor_account_id();
+        if self.deposits.get(&account_id).is_none() {
+            self.deposits.insert(&account_id, &HashMap::new());
+        }
+        self.token.refund_storage(initial_storage);
+    }
+
+    /// Returns tokens deposited by the account and not yet used to join the pool.
+    pub fn get_deposits(&self, account_id: AccountId) -> HashMap<AccountId, U128> {
+        self.deposits
+            .get(&account_id)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(token, amount)| (token, amount.into()))
+            .collect()
+    }
+
+    /// Sends `amount` of deposited `token` back to the caller.
+    pub fn withdraw(&mut self, token: AccountId, amount: U128) -> Promise {
+        let amount: Balance = amount.into();
+        let sender_id = env::predecessor_account_id();
+        let mut deposits = self.deposits.get(&sender_id).unwrap_or_default();
+        self.take_deposit(&mut deposits, &token, amount);
+        self.save_deposits(&sender_id, &deposits);
+        ext_fungible_token::ft_transfer(
+            sender_id.clone(),
+            amount.into(),
+            None,
+            &token,
+            ONE_YOCTO,
+            gas::FT_TRANSFER,
+        )
+        .then(ext_self::on_withdraw(
+            sender_id,
+            token,
+            amount.into(),
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            gas::ON_PUSH_CALLBACK,
+        ))
+    }
+
+    /// Exits the pool with `pool_amount_in` shares, receiving proportional amounts of all tokens.
+    pub fn exit_pool(&mut self, pool_amount_in: U128, min_amounts_out: Vec<U128>) {
+        let pool_amount_in: Balance = pool_amount_in.into();
         assert!(self.finalized, "ERR_NOT_FINALIZED");
+        assert_eq!(
+            min_amounts_out.len(),
+            self.tokens.len(),
+            "ERR_AMOUNTS_MISMATCH"
+        );
 
         let pool_total = self.token.get_total_supply();
-        let exit_fee = poolAmountIn * EXIT_FEE;
-        let p_ai_after_exit_fee = poolAmountIn - exit_fee;
-        let ratio = p_ai_after_exit_fee / pool_total;
+        let exit_fee = bmul(pool_amount_in, EXIT_FEE);
+        let p_ai_after_exit_fee = bsub(pool_amount_in, exit_fee);
+        let ratio = bdiv(p_ai_after_exit_fee, pool_total);
         assert_ne!(ratio, 0, "ERR_MATH_APPROX");
 
-        self.pull_pool_share(env::predecessor_account_id(), poolAmountIn);
+        self.pull_pool_share(env::predecessor_account_id(), pool_amount_in);
         self.push_pool_share(self.factory.clone(), exit_fee);
         self.burn_pool_share(p_ai_after_exit_fee);
 
-        for i in 0..self.tokens.len() {
-            let mut record = self.records.get(&self.tokens[i]).unwrap();
-            let token_amount_out = ratio * record.balance;
+        for (i, token) in self.tokens.clone().iter().enumerate() {
+            let mut record = self.records.get(token).unwrap();
+            let token_amount_out = bmul(ratio, record.balance);
             assert_ne!(token_amount_out, 0, "ERR_MATH_APPROX");
-            assert!(token_amount_out >= minAmountsOut[i], "ERR_LIMIT_OUT");
-            record.balance += token_amount_out;
-            self.push_underlying(
-                self.tokens[i].clone(),
-                env::predecessor_account_id(),
+            assert!(
+                token_amount_out >= min_amounts_out[i].into(),
+                "ERR_LIMIT_OUT"
+            );
+            record.balance = bsub(record.balance, token_amount_out);
+            self.records.insert(token, &record);
+            // Share of the burned pool tokens that corresponds to this token by weight.
+            let pool_shares = bmul(p_ai_after_exit_fee, bdiv(record.denorm, self.total_weight));
+            self.send_tokens(
+                token,
+                &env::predecessor_account_id(),
                 token_amount_out,
+                pool_shares,
             );
         }
     }
 
-    pub fn on_pull(&mut self) -> bool {
-        true
+    /// Exits with exactly `pool_amount_in` shares into single `token_out`.
+    /// Returns amount of `token_out` sent to the caller.
+    pub fn exitswap_pool_amount_in(
+        &mut self,
+        token_out: AccountId,
+        pool_amount_in: U128,
+        min_amount_out: U128,
+    ) -> U128 {
+        let pool_amount_in: Balance = pool_amount_in.into();
+        assert!(self.finalized, "ERR_NOT_FINALIZED");
+        assert!(self.is_bound(token_out.clone()), "ERR_NOT_BOUND");
+
+        let mut out_record = self.records.get(&token_out).unwrap();
+        let token_amount_out = calc_single_out_given_pool_in(
+            out_record.balance,
+            out_record.denorm,
+            self.token.get_total_supply(),
+            self.total_weight,
+            pool_amount_in,
+            self.swap_fee,
+        );
+        assert!(token_amount_out >= min_amount_out.into(), "ERR_LIMIT_OUT");
+        assert!(
+            token_amount_out <= bmul(out_record.balance, MAX_OUT_RATIO),
+            "ERR_MAX_OUT_RATIO"
+        );
+
+        out_record.balance = bsub(out_record.balance, token_amount_out);
+        self.records.insert(&token_out, &out_record);
+        let pool_shares = self.exit_pool_shares(pool_amount_in);
+        self.send_tokens(
+            &token_out,
+            &env::predecessor_account_id(),
+            token_amount_out,
+            pool_shares,
+        );
+        token_amount_out.into()
+    }
+
+    /// Exits into exactly `token_amount_out` of `token_out` paying at most `max_pool_amount_in` shares.
+    /// Returns amount of shares burned.
+    pub fn exitswap_extern_amount_out(
+        &mut self,
+        token_out: AccountId,
+        token_amount_out: U128,
+        max_pool_amount_in: U128,
+    ) -> U128 {
+        let token_amount_out: Balance = token_amount_out.into();
+        assert!(self.finalized, "ERR_NOT_FINALIZED");
+        assert!(self.is_bound(token_out.clone()), "ERR_NOT_BOUND");
+
+        let mut out_record = self.records.get(&token_out).unwrap();
+        assert!(
+            token_amount_out <= bmul(out_record.balance, MAX_OUT_RATIO),
+            "ERR_MAX_OUT_RATIO"
+        );
+        let pool_amount_in = calc_pool_in_given_single_out(
+            out_record.balance,
+            out_record.denorm,
+            self.token.get_total_supply(),
+            self.total_weight,
+            token_amount_out,
+            self.swap_fee,
+        );
+        assert_ne!(pool_amount_in, 0, "ERR_MATH_APPROX");
+        assert!(pool_amount_in <= max_pool_amount_in.into(), "ERR_LIMIT_IN");
+
+        out_record.balance = bsub(out_record.balance, token_amount_out);
+        self.records.insert(&token_out, &out_record);
+        let pool_shares = self.exit_pool_shares(pool_amount_in);
+        self.send_tokens(
+            &token_out,
+            &env::predecessor_account_id(),
+            token_amount_out,
+            pool_shares,
+        );
+        pool_amount_in.into()
+    }
+
+    /// Callback with the pool's balance of the token from `gulp`.
+    /// Returns the new balance of the token in the pool.
+    pub fn on_gulp(&mut self, token: AccountId) -> U128 {
+        assert_self();
+        let balance = match env::promise_result(0) {
+            PromiseResult::Successful(value) => {
+                serde_json::from_slice::<U128>(&value).expect("ERR_WRONG_BALANCE")
+            }
+            _ => env::panic(b"ERR_BALANCE_QUERY_FAILED"),
+        };
+        self.absorb_balance(&token, balance.into()).into()
+    }
+
+    /// Callback after withdrawing deposited tokens, returns them to the deposits on failure.
+    pub fn on_withdraw(&mut self, sender_id: AccountId, token: AccountId, amount: U128) -> bool {
+        assert_self();
+        if is_promise_success() {
+            return true;
+        }
+        env::log(
+            format!(
+                "Failed to withdraw {} of {} to {}, restoring the deposit",
+                amount.0, token, sender_id
+            )
+            .as_bytes(),
+        );
+        self.internal_deposit(&sender_id, &token, amount.into());
+        false
+    }
+
+    /// Callback after sending tokens out of the pool. On failure tokens are still
+    /// owned by the pool, so the balance is restored and `pool_shares` that were burned
+    /// for them are minted back to the receiver. If the token was unbound, tokens are
+    /// credited to the deposits of the receiver to be withdrawn later.
+    pub fn on_push(
+        &mut self,
+        token: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+        pool_shares: U128,
+    ) -> bool {
+        assert_self();
+        if is_promise_success() {
+            return true;
+        }
+        env::log(
+            format!(
+                "Failed to send {} of {} to {}, restoring the balance",
+                amount.0, token, receiver_id
+            )
+            .as_bytes(),
+        );
+        match self.records.get(&token) {
+            Some(mut record) => {
+                record.balance = badd(record.balance, amount.into());
+                self.records.insert(&token, &record);
+            }
+            None => self.internal_deposit(&receiver_id, &token, amount.into()),
+        }
+        if pool_shares.0 > 0 {
+            self.mint_pool_share(pool_shares.into());
+            self.push_pool_share(receiver_id, pool_shares.into());
+        }
+        false
     }
 
-    pub fn on_push(&mut self) -> bool {
-        true
+    /// Callback after sending swapped tokens. On failure reverts the swap and credits
+    /// `token_amount_in` to the deposits of the trader, which can be withdrawn with `withdraw`.
+    pub fn on_swap_out(
+        &mut self,
+        sender_id: AccountId,
+        token_in: AccountId,
+        token_amount_in: U128,
+        token_out: AccountId,
+        token_amount_out: U128,
+    ) -> bool {
+        assert_self();
+        if is_promise_success() {
+            return true;
+        }
+        env::log(
+            format!(
+                "Failed to send {} of {} to {}, reverting the swap to the deposits",
+                token_amount_out.0, token_out, sender_id
+            )
+            .as_bytes(),
+        );
+        let mut in_record = self.records.get(&token_in).unwrap();
+        let mut out_record = self.records.get(&token_out).unwrap();
+        in_record.balance = bsub(in_record.balance, token_amount_in.into());
+        out_record.balance = badd(out_record.balance, token_amount_out.into());
+        self.records.insert(&token_in, &in_record);
+        self.records.insert(&token_out, &out_record);
+        // Sending tokens back could fail as well, so they are kept for `withdraw`.
+        self.internal_deposit(&sender_id, &token_in, token_amount_in.into());
+        false
     }
 }
 
@@ -357,84 +686,502 @@ impl FungibleToken for BPool {
     }
 }
 
+#[ext_contract(ext_fungible_token)]
+pub trait ExtFungibleToken {
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
+
+    fn ft_balance_of(&self, account_id: AccountId) -> U128;
+}
+
 #[ext_contract(ext_self)]
 pub trait ExtSelf {
-    fn on_pull(&mut self) -> bool;
-
-    fn on_push(&mut self) -> bool;
+    fn on_withdraw(&mut self, sender_id: AccountId, token: AccountId, amount: U128) -> bool;
+
+    fn on_gulp(&mut self, token: AccountId) -> U128;
+
+    fn on_push(
+        &mut self,
+        token: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+        pool_shares: U128,
+    ) -> bool;
+
+    fn on_swap_out(
+        &mut self,
+        sender_id: AccountId,
+        token_in: AccountId,
+        token_amount_in: U128,
+        token_out: AccountId,
+        token_amount_out: U128,
+    ) -> bool;
 }
 
 impl BPool {
-    fn pull_underlying(&mut self, token: &AccountId, from: &AccountId, amount: Balance) -> Promise {
-        ext_nep21::transfer_from(
-            from.clone(),
-            env::current_account_id(),
-            amount.into(),
-            token,
-            NO_DEPOSIT,
-            gas::NEP21_TRANSFER_FROM,
+    /// Swaps `token_amount_in` of `token_in` that were already received by the pool.
+    /// Returns amount of `token_out` to send to the trader.
+    fn swap_exact_amount_in(
+        &mut self,
+        token_in: &AccountId,
+        token_amount_in: Balance,
+        token_out: &AccountId,
+        min_amount_out: Balance,
+        max_price: Balance,
+    ) -> Balance {
+        assert!(self.is_bound(token_in.clone()), "ERR_NOT_BOUND");
+        assert!(self.is_bound(token_out.clone()), "ERR_NOT_BOUND");
+        assert!(self.public_swap, "ERR_SWAP_NOT_PUBLIC");
+
+        let mut in_record = self.records.get(token_in).unwrap();
+        let mut out_record = self.records.get(token_out).unwrap();
+
+        assert!(
+            token_amount_in <= bmul(in_record.balance, MAX_IN_RATIO),
+            "ERR_MAX_IN_RATIO"
+        );
+
+        let spot_price_before = calc_spot_price(
+            in_record.balance,
+            in_record.denorm,
+            out_record.balance,
+            out_record.denorm,
+            self.swap_fee,
+        );
+        assert!(spot_price_before <= max_price, "ERR_BAD_LIMIT_PRICE");
+
+        let token_amount_out = calc_out_given_in(
+            in_record.balance,
+            in_record.denorm,
+            out_record.balance,
+            out_record.denorm,
+            token_amount_in,
+            self.swap_fee,
+        );
+        assert!(token_amount_out >= min_amount_out, "ERR_LIMIT_OUT");
+
+        in_record.balance = badd(in_record.balance, token_amount_in);
+        out_record.balance = bsub(out_record.balance, token_amount_out);
+
+        let spot_price_after = calc_spot_price(
+            in_record.balance,
+            in_record.denorm,
+            out_record.balance,
+            out_record.denorm,
+            self.swap_fee,
+        );
+        assert!(spot_price_after >= spot_price_before, "ERR_MATH_APPROX");
+        assert!(spot_price_after <= max_price, "ERR_LIMIT_PRICE");
+        assert!(
+            spot_price_before <= bdiv(token_amount_in, token_amount_out),
+            "ERR_MATH_APPROX"
+        );
+
+        self.records.insert(token_in, &in_record);
+        self.records.insert(token_out, &out_record);
+        env::log(
+            format!(
+                "Swapped {} {} for {} {}",
+                token_amount_in, token_in, token_amount_out, token_out
+            )
+            .as_bytes(),
+        );
+        token_amount_out
+    }
+
+    /// Swaps up to `max_amount_in` of `token_in` that were already received by the pool
+    /// for exactly `token_amount_out` of `token_out`. Returns amount of `token_in` used.
+    fn swap_exact_amount_out(
+        &mut self,
+        token_in: &AccountId,
+        max_amount_in: Balance,
+        token_out: &AccountId,
+        token_amount_out: Balance,
+        max_price: Balance,
+    ) -> Balance {
+        assert!(self.is_bound(token_in.clone()), "ERR_NOT_BOUND");
+        assert!(self.is_bound(token_out.clone()), "ERR_NOT_BOUND");
+        assert!(self.public_swap, "ERR_SWAP_NOT_PUBLIC");
+
+        let mut in_record = self.records.get(token_in).unwrap();
+        let mut out_record = self.records.get(token_out).unwrap();
+
+        assert!(
+            token_amount_out <= bmul(out_record.balance, MAX_OUT_RATIO),
+            "ERR_MAX_OUT_RATIO"
+        );
+
+        let spot_price_before = calc_spot_price(
+            in_record.balance,
+            in_record.denorm,
+            out_record.balance,
+            out_record.denorm,
+            self.swap_fee,
+        );
+        assert!(spot_price_before <= max_price, "ERR_BAD_LIMIT_PRICE");
+
+        let token_amount_in = calc_in_given_out(
+            in_record.balance,
+            in_record.denorm,
+            out_record.balance,
+            out_record.denorm,
+            token_amount_out,
+            self.swap_fee,
+        );
+        assert!(token_amount_in <= max_amount_in, "ERR_LIMIT_IN");
+
+        in_record.balance = badd(in_record.balance, token_amount_in);
+        out_record.balance = bsub(out_record.balance, token_amount_out);
+
+        let spot_price_after = calc_spot_price(
+            in_record.balance,
+            in_record.denorm,
+            out_record.balance,
+            out_record.denorm,
+            self.swap_fee,
+        );
+        assert!(spot_price_after >= spot_price_before, "ERR_MATH_APPROX");
+        assert!(spot_price_after <= max_price, "ERR_LIMIT_PRICE");
+        assert!(
+            spot_price_before <= bdiv(token_amount_in, token_amount_out),
+            "ERR_MATH_APPROX"
+        );
+
+        self.records.insert(token_in, &in_record);
+        self.records.insert(token_out, &out_record);
+        env::log(
+            format!(
+                "Swapped {} {} for {} {}",
+                token_amount_in, token_in, token_amount_out, token_out
+            )
+            .as_bytes(),
+        );
+        token_amount_in
+    }
+
+    /// Joins with `token_amount_in` of `token_in` that were already received by the pool.
+    /// Returns amount of shares minted to `sender_id`.
+    fn joinswap_extern_amount_in(
+        &mut self,
+        sender_id: &AccountId,
+        token_in: &AccountId,
+        token_amount_in: Balance,
+        min_pool_amount_out: Balance,
+    ) -> Balance {
+        assert!(self.finalized, "ERR_NOT_FINALIZED");
+        assert!(self.is_bound(token_in.clone()), "ERR_NOT_BOUND");
+
+        let mut in_record = self.records.get(token_in).unwrap();
+        assert!(
+            token_amount_in <= bmul(in_record.balance, MAX_IN_RATIO),
+            "ERR_MAX_IN_RATIO"
+        );
+        let pool_amount_out = calc_pool_out_given_single_in(
+            in_record.balance,
+            in_record.denorm,
+            self.token.get_total_supply(),
+            self.total_weight,
+            token_amount_in,
+            self.swap_fee,
+        );
+        assert!(pool_amount_out >= min_pool_amount_out, "ERR_LIMIT_OUT");
+
+        in_record.balance = badd(in_record.balance, token_amount_in);
+        self.records.insert(token_in, &in_record);
+        self.mint_pool_share(pool_amount_out);
+        self.push_pool_share(sender_id.clone(), pool_amount_out);
+        pool_amount_out
+    }
+
+    /// Joins for exactly `pool_amount_out` shares paying at most `max_amount_in` of `token_in`
+    /// that were already received by the pool. Returns amount of `token_in` used.
+    fn joinswap_pool_amount_out(
+        &mut self,
+        sender_id: &AccountId,
+        token_in: &AccountId,
+        max_amount_in: Balance,
+        pool_amount_out: Balance,
+    ) -> Balance {
+        assert!(self.finalized, "ERR_NOT_FINALIZED");
+        assert!(self.is_bound(token_in.clone()), "ERR_NOT_BOUND");
+
+        let mut in_record = self.records.get(token_in).unwrap();
+        let token_amount_in = calc_single_in_given_pool_out(
+            in_record.balance,
+            in_record.denorm,
+            self.token.get_total_supply(),
+            self.total_weight,
+            pool_amount_out,
+            self.swap_fee,
+        );
+        assert_ne!(token_amount_in, 0, "ERR_MATH_APPROX");
+        assert!(token_amount_in <= max_amount_in, "ERR_LIMIT_IN");
+        assert!(
+            token_amount_in <= bmul(in_record.balance, MAX_IN_RATIO),
+            "ERR_MAX_IN_RATIO"
+        );
+
+        in_record.balance = badd(in_record.balance, token_amount_in);
+        self.records.insert(token_in, &in_record);
+        self.mint_pool_share(pool_amount_out);
+        self.push_pool_share(sender_id.clone(), pool_amount_out);
+        token_amount_in
+    }
+
+    /// Takes `pool_amount_in` shares from the caller, burns them except for the exit fee,
+    /// that goes to the factory. Returns amount of burned shares.
+    fn exit_pool_shares(&mut self, pool_amount_in: Balance) -> Balance {
+        let exit_fee = bmul(pool_amount_in, EXIT_FEE);
+        let burned = bsub(pool_amount_in, exit_fee);
+        self.pull_pool_share(env::predecessor_account_id(), pool_amount_in);
+        self.burn_pool_share(burned);
+        self.push_pool_share(self.factory.clone(), exit_fee);
+        burned
+    }
+
+    /// Sends swapped tokens to the trader, reverting the swap if the transfer fails.
+    fn send_swap_output(
+        &self,
+        sender_id: &AccountId,
+        token_in: &AccountId,
+        token_amount_in: Balance,
+        token_out: &AccountId,
+        token_amount_out: Balance,
+    ) -> Promise {
+        ext_fungible_token::ft_transfer(
+            sender_id.clone(),
+            token_amount_out.into(),
+            None,
+            token_out,
+            ONE_YOCTO,
+            gas::FT_TRANSFER,
         )
-        .then(ext_self::on_pull(
+        .then(ext_self::on_swap_out(
+            sender_id.clone(),
+            token_in.clone(),
+            token_amount_in.into(),
+            token_out.clone(),
+            token_amount_out.into(),
             &env::current_account_id(),
             NO_DEPOSIT,
-            gas::ON_PULL_CALLBACK,
+            gas::ON_PUSH_CALLBACK,
         ))
     }
 
-    fn push_underlying(&mut self, token: AccountId, to: AccountId, amount: Balance) -> Promise {
-        ext_nep21::transfer(
-            to.clone(),
+    /// Sends NEP-141 tokens from the pool, `on_push` restores the balance on failure.
+    fn send_tokens(
+        &self,
+        token: &AccountId,
+        receiver_id: &AccountId,
+        amount: Balance,
+        pool_shares: Balance,
+    ) -> Promise {
+        ext_fungible_token::ft_transfer(
+            receiver_id.clone(),
             amount.into(),
-            &token,
-            NO_DEPOSIT,
-            gas::NEP21_TRANSFER,
+            None,
+            token,
+            ONE_YOCTO,
+            gas::FT_TRANSFER,
         )
         .then(ext_self::on_push(
+            token.clone(),
+            receiver_id.clone(),
+            amount.into(),
+            pool_shares.into(),
             &env::current_account_id(),
             NO_DEPOSIT,
             gas::ON_PUSH_CALLBACK,
         ))
     }
 
-    fn mint_pool_share(&mut self, amount: Balance) {
-        self.token.mint(env::current_account_id(), amount)
+    fn set_denorm(&mut self, record: &mut Record, denorm: Weight) {
+        self.total_weight = badd(bsub(self.total_weight, record.denorm), denorm);
+        record.denorm = denorm;
     }
 
-    fn burn_pool_share(&mut self, amount: Balance) {
-        self.token.burn(env::current_account_id(), amount)
-    }
+    /// Checks that `controller_id` can change weight of the `token`, returns it's record.
+    fn assert_rebind(
+        &self,
+        controller_id: &AccountId,
+        token: &AccountId,
+        denorm: Weight,
+    ) -> Record {
+        assert_eq!(controller_id, &self.controller, "ERR_NOT_CONTROLLER");
+        assert!(self.is_bound(token.clone()), "ERR_NOT_BOUND");
+        assert!(!self.finalized, "ERR_IS_FINALIZED");
+        assert!(
+            self.weight_update.is_none(),
+            "ERR_WEIGHT_UPDATE_IN_PROGRESS"
+        );
 
-    fn pull_pool_share(&mut self, from: AccountId, amount: Balance) {
-        self.token
-            .transfer_from(from, env::current_account_id(), amount)
+        assert!(denorm >= MIN_WEIGHT, "ERR_MIN_WEIGHT");
+        assert!(denorm <= MAX_WEIGHT, "ERR_MAX_WEIGHT");
+
+        let record = self.records.get(token).unwrap();
+        assert!(
+            badd(bsub(self.total_weight, record.denorm), denorm) <= MAX_TOTAL_WEIGHT,
+            "ERR_MAX_TOTAL_WEIGHT"
+        );
+        record
     }
 
-    fn push_pool_share(&mut self, to: AccountId, amount: Balance) {
-        self.token
-            .transfer_from(env::current_account_id(), to, amount)
+    /// Checks that `token` can be bound.
+    fn assert_bind(&self, token: &AccountId) {
+        assert!(!self.is_bound(token.clone()), "ERR_IS_BOUND");
+        assert!(!self.finalized, "ERR_IS_FINALIZED");
+        assert!(
+            self.weight_update.is_none(),
+            "ERR_WEIGHT_UPDATE_IN_PROGRESS"
+        );
+        assert!(self.tokens.len() < MAX_BOUND_TOKENS, "ERR_MAX_TOKENS");
     }
-}
 
-#[cfg(not(target_arch = "wasm32"))]
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use near_sdk::{testing_env, MockedBlockchain, VMContext};
+    /// Binds `token` approved by `bind` with `balance` that was already received by the pool.
+    fn bind_received(&mut self, sender_id: &AccountId, token: &AccountId, balance: Balance) {
+        assert_eq!(sender_id, &self.controller, "ERR_NOT_CONTROLLER");
+        let denorm = self
+            .pending_binds
+            .remove(token)
+            .expect("ERR_BIND_NOT_APPROVED");
+        self.assert_bind(token);
 
-    fn pool_account() -> AccountId {
-        "pool".to_string()
-    }
-    fn factory_account() -> AccountId {
-        "factory".to_string()
+        self.records.insert(
+            token,
+            &Record {
+                bound: true,
+                index: self.tokens.len() as u64,
+                denorm: 0,
+                balance: 0,
+            },
+        );
+        self.tokens.push(token.clone());
+        self.rebind_received(sender_id, token, balance, denorm);
     }
-    fn token1_account() -> AccountId {
-        "token1".to_string()
+
+    /// Adds `amount` that was already received by the pool to the balance of `token`
+    /// and sets it's weight.
+    fn rebind_received(
+        &mut self,
+        sender_id: &AccountId,
+        token: &AccountId,
+        amount: Balance,
+        denorm: Weight,
+    ) {
+        let mut record = self.assert_rebind(sender_id, token, denorm);
+        record.balance = badd(record.balance, amount);
+        assert!(record.balance >= MIN_BALANCE, "ERR_MIN_BALANCE");
+        self.set_denorm(&mut record, denorm);
+        self.records.insert(token, &record);
     }
-    fn token2_account() -> AccountId {
-        "token2".to_string()
+
+    /// Credits `amount` of `token` received from `account_id` for `join_pool`.
+    /// Only callbacks returning tokens credit accounts that are not registered.
+    fn internal_deposit(&mut self, account_id: &AccountId, token: &AccountId, amount: Balance) {
+        let mut deposits = self.deposits.get(account_id).unwrap_or_default();
+        let deposit = deposits.entry(token.clone()).or_insert(0);
+        *deposit = badd(*deposit, amount);
+        self.deposits.insert(account_id, &deposits);
+        let total = self.deposit_totals.get(token).unwrap_or(0);
+        self.deposit_totals.insert(token, &badd(total, amount));
     }
 
-    pub fn get_context(
+    /// Takes `amount` of `token` from `deposits` of the account, the caller saves them.
+    fn take_deposit(
+        &mut self,
+        deposits: &mut HashMap<AccountId, Balance>,
+        token: &AccountId,
+        amount: Balance,
+    ) {
+        let deposit = deposits.get(token).cloned().unwrap_or(0);
+        assert!(deposit >= amount, "ERR_NOT_ENOUGH_DEPOSIT");
+        if deposit == amount {
+            deposits.remove(token);
+        } else {
+            deposits.insert(token.clone(), deposit - amount);
+        }
+        let total = self.deposit_totals.get(token).unwrap();
+        self.deposit_totals.insert(token, &(total - amount));
+    }
+
+    /// Saves deposits of the account, empty ones are kept so the account stays registered.
+    fn save_deposits(&mut self, account_id: &AccountId, deposits: &HashMap<AccountId, Balance>) {
+        self.deposits.insert(account_id, deposits);
+    }
+
+    /// Sets balance of the token to `balance` held by the pool minus tokens deposited for
+    /// `join_pool`. Balance is never lowered, as outgoing transfers may still be in flight.
+    fn absorb_balance(&mut self, token: &AccountId, balance: Balance) -> Balance {
+        let mut record = self.records.get(token).expect("ERR_NOT_BOUND");
+        let available = balance.saturating_sub(self.deposit_totals.get(token).unwrap_or(0));
+        if available > record.balance {
+            env::log(format!("Absorbed {} of {}", available - record.balance, token).as_bytes());
+            record.balance = available;
+            self.records.insert(token, &record);
+        }
+        record.balance
+    }
+
+    /// Removes the token from the pool, moving the last token to it's index.
+    fn remove_token(&mut self, token: &AccountId) {
+        let record = self.records.remove(token).expect("ERR_NOT_BOUND");
+        let index = record.index as usize;
+        self.tokens.swap_remove(index);
+        if index < self.tokens.len() {
+            let mut moved_record = self.records.get(&self.tokens[index]).unwrap();
+            moved_record.index = record.index;
+            self.records.insert(&self.tokens[index], &moved_record);
+        }
+        self.total_weight = bsub(self.total_weight, record.denorm);
+    }
+
+    fn mint_pool_share(&mut self, amount: Balance) {
+        self.token.mint(env::current_account_id(), amount)
+    }
+
+    fn burn_pool_share(&mut self, amount: Balance) {
+        self.token.burn(env::current_account_id(), amount)
+    }
+
+    fn pull_pool_share(&mut self, from: AccountId, amount: Balance) {
+        self.move_pool_share(&from, &env::current_account_id(), amount);
+    }
+
+    fn push_pool_share(&mut self, to: AccountId, amount: Balance) {
+        self.move_pool_share(&env::current_account_id(), &to, amount);
+    }
+
+    /// Moves shares between accounts without checking allowance, as the pool itself moves them.
+    fn move_pool_share(&mut self, from: &AccountId, to: &AccountId, amount: Balance) {
+        let mut from_account = self.token.get_account(from);
+        assert!(from_account.balance >= amount, "ERR_INSUFFICIENT_BAL");
+        from_account.balance -= amount;
+        self.token.set_account(from, &from_account);
+        let mut to_account = self.token.get_account(to);
+        to_account.balance = badd(to_account.balance, amount);
+        self.token.set_account(to, &to_account);
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::{testing_env, MockedBlockchain, VMContext};
+
+    fn pool_account() -> AccountId {
+        "pool".to_string()
+    }
+    fn factory_account() -> AccountId {
+        "factory".to_string()
+    }
+    fn token1_account() -> AccountId {
+        "token1".to_string()
+    }
+    fn token2_account() -> AccountId {
+        "token2".to_string()
+    }
+
+    pub fn get_context(
         predecessor_account_id: AccountId,
         account_balance: u128,
         account_locked_balance: u128,
@@ -464,23 +1211,681 @@ mod tests {
         amount * 10u128.pow(24)
     }
 
+    /// Sends `amount` of `token` from `sender_id` to the pool with given message.
+    fn transfer_call(
+        pool: &mut BPool,
+        token: AccountId,
+        sender_id: AccountId,
+        amount: Balance,
+        msg: &str,
+    ) -> U128 {
+        testing_env!(get_context(token, to_yocto(10), 0, false));
+        let refund = pool.ft_on_transfer(sender_id.clone(), amount.into(), msg.to_string());
+        testing_env!(get_context(sender_id, to_yocto(10), 0, false));
+        refund
+    }
+
+    /// Registers the account for deposits, attaching more than needed for the storage.
+    fn register_deposits(pool: &mut BPool, account_id: AccountId) {
+        let mut context = get_context(account_id, to_yocto(10), 0, false);
+        context.attached_deposit = to_yocto(1);
+        testing_env!(context);
+        pool.register_deposits();
+    }
+
+    /// Binds the token by approving it and sending `balance` of it from the controller.
+    fn bind(pool: &mut BPool, token: AccountId, balance: Balance, denorm: Weight) {
+        testing_env!(get_context(factory_account(), to_yocto(10), 0, false));
+        pool.bind(token.clone(), denorm.into());
+        transfer_call(pool, token, factory_account(), balance, r#""bind""#);
+    }
+
     #[test]
     fn test_setup_pool() {
         let context = get_context(factory_account(), to_yocto(10), 0, false);
         testing_env!(context.clone());
         let mut pool = BPool::new();
-        assert_eq!(pool.getController(), factory_account());
-        pool.bind(
-            token1_account(),
-            to_yocto(50_000).into(),
+        assert_eq!(pool.get_controller(), factory_account());
+        bind(&mut pool, token1_account(), to_yocto(50_000), to_yocto(10));
+        bind(
+            &mut pool,
+            token2_account(),
+            to_yocto(1_000_000),
+            to_yocto(10),
+        );
+        pool.finalize();
+        // 0.05 / (1 - MIN_FEE)
+        assert_eq!(
+            pool.get_spot_price(token1_account(), token2_account()),
+            50_000_050_000_050_000_050_000.into()
+        );
+        let info = pool.get_pool_info();
+        assert_eq!(info.tokens, vec![token1_account(), token2_account()]);
+        assert_eq!(
+            info.balances,
+            vec![to_yocto(50_000).into(), to_yocto(1_000_000).into()]
+        );
+        assert_eq!(info.total_weight, to_yocto(20).into());
+        assert_eq!(info.total_supply, INIT_POOL_SUPPLY.into());
+        assert!(info.finalized && info.public_swap);
+        assert_eq!(
+            pool.get_normalized_weight(token1_account()),
+            (BONE / 2).into()
+        );
+    }
+
+    #[test]
+    fn test_swap() {
+        let context = get_context(factory_account(), to_yocto(10), 0, false);
+        testing_env!(context.clone());
+        let mut pool = BPool::new();
+        bind(&mut pool, token1_account(), to_yocto(50_000), to_yocto(10));
+        bind(
+            &mut pool,
+            token2_account(),
+            to_yocto(1_000_000),
+            to_yocto(10),
+        );
+        pool.finalize();
+
+        // Sending 1000 of token1 to swap for token2.
+        let context = get_context(token1_account(), to_yocto(10), 0, false);
+        testing_env!(context.clone());
+        let refund = pool.ft_on_transfer(
+            "user".to_string(),
+            to_yocto(1_000).into(),
+            format!(
+                r#"{{"swap_exact_amount_in": {{"token_out": "{}", "min_amount_out": "{}", "max_price": "{}"}}}}"#,
+                token2_account(),
+                to_yocto(19_000),
+                BONE
+            ),
+        );
+        assert_eq!(refund, 0.into());
+        let token_amount_out = 19_607_823_913_878_900_272_135_000_000;
+        assert_eq!(
+            pool.get_token_balance(token1_account()),
+            to_yocto(51_000).into()
+        );
+        assert_eq!(
+            pool.get_token_balance(token2_account()),
+            (to_yocto(1_000_000) - token_amount_out).into()
+        );
+
+        // Sending up to 1000 of token1 to get exactly 10000 of token2, the rest is refunded.
+        let refund = pool.ft_on_transfer(
+            "user".to_string(),
+            to_yocto(1_000).into(),
+            format!(
+                r#"{{"swap_exact_amount_out": {{"token_out": "{}", "token_amount_out": "{}", "max_price": "{}"}}}}"#,
+                token2_account(),
+                to_yocto(10_000),
+                BONE
+            ),
+        );
+        assert_eq!(refund, 474_438_765_512_833_144_460_914_461.into());
+        assert_eq!(
+            pool.get_token_balance(token2_account()),
+            (to_yocto(990_000) - token_amount_out).into()
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_LIMIT_OUT")]
+    fn test_swap_limit_out() {
+        let context = get_context(factory_account(), to_yocto(10), 0, false);
+        testing_env!(context.clone());
+        let mut pool = BPool::new();
+        bind(&mut pool, token1_account(), to_yocto(50), to_yocto(10));
+        bind(&mut pool, token2_account(), to_yocto(50), to_yocto(10));
+        pool.finalize();
+        let context = get_context(token1_account(), to_yocto(10), 0, false);
+        testing_env!(context.clone());
+        pool.ft_on_transfer(
+            "user".to_string(),
             to_yocto(10).into(),
+            format!(
+                r#"{{"swap_exact_amount_in": {{"token_out": "{}", "min_amount_out": "{}", "max_price": "{}"}}}}"#,
+                token2_account(),
+                to_yocto(10),
+                2 * BONE
+            ),
         );
-        pool.bind(
+    }
+
+    #[test]
+    fn test_joinswap_exitswap() {
+        let context = get_context(factory_account(), to_yocto(10), 0, false);
+        testing_env!(context.clone());
+        let mut pool = BPool::new();
+        bind(&mut pool, token1_account(), to_yocto(50_000), to_yocto(10));
+        bind(
+            &mut pool,
             token2_account(),
-            to_yocto(1_000_000).into(),
+            to_yocto(1_000_000),
+            to_yocto(10),
+        );
+        pool.finalize();
+        assert_eq!(pool.get_balance(factory_account()), INIT_POOL_SUPPLY.into());
+
+        // Join with 1000 of token1.
+        let expected_pool_out = calc_pool_out_given_single_in(
+            to_yocto(50_000),
+            to_yocto(10),
+            INIT_POOL_SUPPLY,
+            to_yocto(20),
+            to_yocto(1_000),
+            MIN_FEE,
+        );
+        let context = get_context(token1_account(), to_yocto(10), 0, false);
+        testing_env!(context.clone());
+        let refund = pool.ft_on_transfer(
+            "user".to_string(),
+            to_yocto(1_000).into(),
+            format!(
+                r#"{{"joinswap_extern_amount_in": {{"min_pool_amount_out": "{}"}}}}"#,
+                BONE
+            ),
+        );
+        assert_eq!(refund, 0.into());
+        assert_eq!(
+            pool.get_balance("user".to_string()),
+            expected_pool_out.into()
+        );
+        assert_eq!(
+            pool.get_token_balance(token1_account()),
+            to_yocto(51_000).into()
+        );
+
+        // Exit all shares into token2.
+        let context = get_context("user".to_string(), to_yocto(10), 0, false);
+        testing_env!(context.clone());
+        let token_amount_out =
+            pool.exitswap_pool_amount_in(token2_account(), expected_pool_out.into(), 1.into());
+        assert_eq!(pool.get_balance("user".to_string()), 0.into());
+        assert_eq!(pool.get_total_supply(), INIT_POOL_SUPPLY.into());
+        assert_eq!(
+            pool.get_token_balance(token2_account()),
+            (to_yocto(1_000_000) - token_amount_out.0).into()
+        );
+        // Roughly 20000 of token2 at 0.05 price, minus fees.
+        assert!(token_amount_out.0 < to_yocto(19_608) && token_amount_out.0 > to_yocto(19_000));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_MAX_IN_RATIO")]
+    fn test_joinswap_max_in_ratio() {
+        let context = get_context(factory_account(), to_yocto(10), 0, false);
+        testing_env!(context.clone());
+        let mut pool = BPool::new();
+        bind(&mut pool, token1_account(), to_yocto(50), to_yocto(10));
+        bind(&mut pool, token2_account(), to_yocto(50), to_yocto(10));
+        pool.finalize();
+        let context = get_context(token1_account(), to_yocto(10), 0, false);
+        testing_env!(context.clone());
+        pool.ft_on_transfer(
+            "user".to_string(),
+            to_yocto(26).into(),
+            r#"{"joinswap_extern_amount_in": {"min_pool_amount_out": "0"}}"#.to_string(),
+        );
+    }
+
+    #[test]
+    fn test_rebind() {
+        let context = get_context(factory_account(), to_yocto(10), 0, false);
+        testing_env!(context.clone());
+        let mut pool = BPool::new();
+        bind(&mut pool, token1_account(), to_yocto(50), to_yocto(10));
+        bind(&mut pool, token2_account(), to_yocto(50), to_yocto(10));
+
+        // Sending more tokens increases the balance.
+        transfer_call(
+            &mut pool,
+            token1_account(),
+            factory_account(),
+            to_yocto(10),
+            &format!(r#"{{"rebind": {{"denorm": "{}"}}}}"#, to_yocto(20)),
+        );
+        assert_eq!(
+            pool.get_token_balance(token1_account()),
+            to_yocto(60).into()
+        );
+        assert_eq!(pool.get_total_denormalized_weight(), to_yocto(30).into());
+
+        // Lowering the balance sends tokens back to the controller.
+        pool.rebind(token1_account(), to_yocto(40).into(), to_yocto(10).into());
+        assert_eq!(
+            pool.get_token_balance(token1_account()),
+            to_yocto(40).into()
+        );
+        assert_eq!(pool.get_total_denormalized_weight(), to_yocto(20).into());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_CONTROLLER")]
+    fn test_bind_not_controller() {
+        let context = get_context(factory_account(), to_yocto(10), 0, false);
+        testing_env!(context.clone());
+        let mut pool = BPool::new();
+        testing_env!(get_context("user".to_string(), to_yocto(10), 0, false));
+        pool.bind(token1_account(), to_yocto(10).into());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_BIND_NOT_APPROVED")]
+    fn test_bind_not_approved() {
+        let context = get_context(factory_account(), to_yocto(10), 0, false);
+        testing_env!(context.clone());
+        let mut pool = BPool::new();
+        pool.bind(token1_account(), to_yocto(10).into());
+        // Any contract can call `ft_on_transfer` claiming that the controller sent tokens,
+        // but only the approved token can be bound.
+        transfer_call(
+            &mut pool,
+            "fake_token".to_string(),
+            factory_account(),
+            to_yocto(50),
+            r#""bind""#,
+        );
+    }
+
+    #[test]
+    fn test_join_pool() {
+        let context = get_context(factory_account(), to_yocto(10), 0, false);
+        testing_env!(context.clone());
+        let mut pool = BPool::new();
+        bind(&mut pool, token1_account(), to_yocto(50), to_yocto(10));
+        bind(&mut pool, token2_account(), to_yocto(50), to_yocto(10));
+        pool.finalize();
+
+        let user = "user".to_string();
+        register_deposits(&mut pool, user.clone());
+        transfer_call(
+            &mut pool,
+            token1_account(),
+            user.clone(),
+            to_yocto(60),
+            r#""deposit""#,
+        );
+        transfer_call(
+            &mut pool,
+            token2_account(),
+            user.clone(),
+            to_yocto(50),
+            r#""deposit""#,
+        );
+        assert_eq!(pool.get_deposits(user.clone()).len(), 2);
+
+        // Doubling the pool takes 50 of each token from the deposits.
+        pool.join_pool(
+            INIT_POOL_SUPPLY.into(),
+            vec![to_yocto(50).into(), to_yocto(50).into()],
+        );
+        assert_eq!(pool.get_balance(user.clone()), INIT_POOL_SUPPLY.into());
+        assert_eq!(
+            pool.get_token_balance(token1_account()),
+            to_yocto(100).into()
+        );
+        assert_eq!(
+            pool.get_token_balance(token2_account()),
+            to_yocto(100).into()
+        );
+        let deposits = pool.get_deposits(user.clone());
+        assert_eq!(deposits.len(), 1);
+        assert_eq!(deposits[&token1_account()], to_yocto(10).into());
+
+        pool.withdraw(token1_account(), to_yocto(10).into());
+        assert!(pool.get_deposits(user).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_ENOUGH_DEPOSIT")]
+    fn test_join_pool_not_enough_deposit() {
+        let context = get_context(factory_account(), to_yocto(10), 0, false);
+        testing_env!(context.clone());
+        let mut pool = BPool::new();
+        bind(&mut pool, token1_account(), to_yocto(50), to_yocto(10));
+        bind(&mut pool, token2_account(), to_yocto(50), to_yocto(10));
+        pool.finalize();
+
+        let user = "user".to_string();
+        register_deposits(&mut pool, user.clone());
+        transfer_call(
+            &mut pool,
+            token1_account(),
+            user.clone(),
+            to_yocto(50),
+            r#""deposit""#,
+        );
+        pool.join_pool(
+            INIT_POOL_SUPPLY.into(),
+            vec![to_yocto(50).into(), to_yocto(50).into()],
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_REGISTERED")]
+    fn test_deposit_not_registered() {
+        let context = get_context(factory_account(), to_yocto(10), 0, false);
+        testing_env!(context.clone());
+        let mut pool = BPool::new();
+        bind(&mut pool, token1_account(), to_yocto(50), to_yocto(10));
+        // Deposits of unregistered accounts would grow the storage for free.
+        transfer_call(
+            &mut pool,
+            token1_account(),
+            "user".to_string(),
+            1,
+            r#""deposit""#,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "The required attached deposit is")]
+    fn test_register_deposits_storage() {
+        let context = get_context(factory_account(), to_yocto(10), 0, false);
+        testing_env!(context.clone());
+        let mut pool = BPool::new();
+        testing_env!(get_context("user".to_string(), to_yocto(10), 0, false));
+        pool.register_deposits();
+    }
+
+    #[test]
+    fn test_unbind() {
+        let context = get_context(factory_account(), to_yocto(10), 0, false);
+        testing_env!(context.clone());
+        let mut pool = BPool::new();
+        bind(&mut pool, token1_account(), to_yocto(50), to_yocto(10));
+        bind(&mut pool, token2_account(), to_yocto(50), to_yocto(5));
+        bind(&mut pool, "token3".to_string(), to_yocto(50), to_yocto(15));
+
+        // Last token takes the place of the unbound one.
+        pool.unbind(token1_account());
+        assert!(!pool.is_bound(token1_account()));
+        assert_eq!(
+            pool.get_current_tokens(),
+            vec!["token3".to_string(), token2_account()]
+        );
+        assert_eq!(pool.get_total_denormalized_weight(), to_yocto(20).into());
+
+        // Indices are updated, so unbinding the moved token works.
+        pool.unbind("token3".to_string());
+        assert_eq!(pool.get_current_tokens(), vec![token2_account()]);
+        assert_eq!(pool.get_total_denormalized_weight(), to_yocto(5).into());
+
+        // Token can be bound again.
+        bind(&mut pool, token1_account(), to_yocto(50), to_yocto(10));
+        assert_eq!(
+            pool.get_current_tokens(),
+            vec![token2_account(), token1_account()]
+        );
+    }
+
+    #[test]
+    fn test_absorb_balance() {
+        let context = get_context(factory_account(), to_yocto(10), 0, false);
+        testing_env!(context.clone());
+        let mut pool = BPool::new();
+        bind(&mut pool, token1_account(), to_yocto(50), to_yocto(10));
+        bind(&mut pool, token2_account(), to_yocto(50), to_yocto(10));
+        pool.finalize();
+        register_deposits(&mut pool, "user".to_string());
+        transfer_call(
+            &mut pool,
+            token1_account(),
+            "user".to_string(),
+            to_yocto(5),
+            r#""deposit""#,
+        );
+
+        // 10 tokens were sent directly, deposit is not absorbed.
+        assert_eq!(
+            pool.absorb_balance(&token1_account(), to_yocto(65)),
+            to_yocto(60)
+        );
+        assert_eq!(
+            pool.get_token_balance(token1_account()),
+            to_yocto(60).into()
+        );
+        // Lower balance of the token contract doesn't change the pool.
+        assert_eq!(
+            pool.absorb_balance(&token1_account(), to_yocto(20)),
+            to_yocto(60)
+        );
+    }
+
+    #[test]
+    fn test_update_weights_gradually() {
+        let context = get_context(factory_account(), to_yocto(10), 0, false);
+        testing_env!(context.clone());
+        let mut pool = BPool::new();
+        bind(&mut pool, token1_account(), to_yocto(50), to_yocto(10));
+        bind(&mut pool, token2_account(), to_yocto(50), to_yocto(10));
+        pool.finalize();
+        pool.update_weights_gradually(
+            vec![to_yocto(40).into(), to_yocto(10).into()],
+            100.into(),
+            200.into(),
+        );
+
+        // Half way through token1 has weight 25.
+        let mut context = get_context("user".to_string(), to_yocto(10), 0, false);
+        context.block_timestamp = 150;
+        testing_env!(context.clone());
+        pool.poke_weights();
+        assert_eq!(
+            pool.get_denormalized_weight(token1_account()),
+            to_yocto(25).into()
+        );
+        assert_eq!(pool.get_total_denormalized_weight(), to_yocto(35).into());
+        assert!(pool.get_weight_update().is_some());
+
+        // After the end weights are final and the update is done.
+        context.block_timestamp = 250;
+        testing_env!(context.clone());
+        pool.poke_weights();
+        assert_eq!(
+            pool.get_denormalized_weight(token1_account()),
+            to_yocto(40).into()
+        );
+        assert_eq!(pool.get_total_denormalized_weight(), to_yocto(50).into());
+        assert!(pool.get_weight_update().is_none());
+        assert_eq!(
+            pool.get_token_balance(token1_account()),
+            to_yocto(50).into()
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_MAX_TOTAL_WEIGHT")]
+    fn test_update_weights_max_total_weight() {
+        let context = get_context(factory_account(), to_yocto(10), 0, false);
+        testing_env!(context.clone());
+        let mut pool = BPool::new();
+        bind(&mut pool, token1_account(), to_yocto(50), to_yocto(10));
+        bind(&mut pool, token2_account(), to_yocto(50), to_yocto(10));
+        pool.update_weights_gradually(
+            vec![to_yocto(45).into(), to_yocto(10).into()],
+            100.into(),
+            200.into(),
+        );
+    }
+
+    #[test]
+    fn test_join_exit_pool_conservation() {
+        let context = get_context(factory_account(), to_yocto(10), 0, false);
+        testing_env!(context.clone());
+        let mut pool = BPool::new();
+        bind(&mut pool, token1_account(), to_yocto(50_000), to_yocto(10));
+        bind(
+            &mut pool,
+            token2_account(),
+            to_yocto(1_000_000),
+            to_yocto(40),
+        );
+        pool.finalize();
+
+        let user = "user".to_string();
+        register_deposits(&mut pool, user.clone());
+        transfer_call(
+            &mut pool,
+            token1_account(),
+            user.clone(),
+            to_yocto(5_000),
+            r#""deposit""#,
+        );
+        transfer_call(
+            &mut pool,
+            token2_account(),
+            user.clone(),
+            to_yocto(100_000),
+            r#""deposit""#,
+        );
+
+        // 10 shares out of 100 take 10% of each balance, not all or nothing.
+        pool.join_pool(
             to_yocto(10).into(),
+            vec![to_yocto(5_000).into(), to_yocto(100_000).into()],
+        );
+        assert_eq!(pool.get_balance(user.clone()), to_yocto(10).into());
+        assert_eq!(
+            pool.get_token_balance(token1_account()),
+            to_yocto(55_000).into()
+        );
+        assert_eq!(
+            pool.get_token_balance(token2_account()),
+            to_yocto(1_100_000).into()
+        );
+        assert!(pool.get_deposits(user.clone()).is_empty());
+
+        // Exiting with the same shares returns at most what was joined with.
+        pool.exit_pool(
+            to_yocto(10).into(),
+            vec![to_yocto(4_999).into(), to_yocto(99_999).into()],
+        );
+        assert_eq!(pool.get_balance(user), 0.into());
+        assert_eq!(pool.get_total_supply(), INIT_POOL_SUPPLY.into());
+        // ratio = bdiv(10, 110) rounds down to 0.090909090909090909090909.
+        let ratio = 90_909_090_909_090_909_090_909;
+        let token1_out = ratio * 55_000;
+        let token2_out = ratio * 1_100_000;
+        assert_eq!(
+            pool.get_token_balance(token1_account()),
+            (to_yocto(55_000) - token1_out).into()
+        );
+        assert_eq!(
+            pool.get_token_balance(token2_account()),
+            (to_yocto(1_100_000) - token2_out).into()
+        );
+        // Rounding is in favor of the pool.
+        assert!(token1_out <= to_yocto(5_000) && to_yocto(5_000) - token1_out < BONE / 1_000_000);
+        assert!(
+            token2_out <= to_yocto(100_000) && to_yocto(100_000) - token2_out < BONE / 1_000_000
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_LIMIT_IN")]
+    fn test_join_pool_limit_in() {
+        let context = get_context(factory_account(), to_yocto(10), 0, false);
+        testing_env!(context.clone());
+        let mut pool = BPool::new();
+        bind(&mut pool, token1_account(), to_yocto(50), to_yocto(10));
+        bind(&mut pool, token2_account(), to_yocto(50), to_yocto(10));
+        pool.finalize();
+        let user = "user".to_string();
+        register_deposits(&mut pool, user.clone());
+        transfer_call(
+            &mut pool,
+            token1_account(),
+            user.clone(),
+            to_yocto(50),
+            r#""deposit""#,
+        );
+        transfer_call(
+            &mut pool,
+            token2_account(),
+            user,
+            to_yocto(50),
+            r#""deposit""#,
         );
+        // 1 share out of 100 takes 0.5 of each token.
+        pool.join_pool(to_yocto(1).into(), vec![to_yocto(1).into(), BONE.into()]);
+        pool.join_pool(
+            to_yocto(1).into(),
+            vec![to_yocto(1).into(), (BONE / 2 - 1).into()],
+        );
+    }
+
+    #[test]
+    fn test_swap_fee_governance() {
+        let context = get_context(factory_account(), to_yocto(10), 0, false);
+        testing_env!(context.clone());
+        let mut pool = BPool::new();
+        bind(&mut pool, token1_account(), to_yocto(50), to_yocto(10));
+        bind(&mut pool, token2_account(), to_yocto(50), to_yocto(10));
         pool.finalize();
-        assert_eq!(pool.getSpotPrice(token1_account(), token2_account()), 1);
+        let user = "user".to_string();
+        pool.move_pool_share(&factory_account(), &user, to_yocto(20));
+
+        // Factory votes for with 30 shares, user against with 20.
+        let new_fee = MAX_FEE / 10;
+        assert_eq!(pool.propose_swap_fee(new_fee.into()), 1.into());
+        pool.vote_swap_fee(true, to_yocto(30).into());
+        assert_eq!(pool.get_balance(factory_account()), to_yocto(50).into());
+        let mut context = get_context(user.clone(), to_yocto(10), 0, false);
+        testing_env!(context.clone());
+        pool.vote_swap_fee(false, to_yocto(20).into());
+        let proposal = pool.get_fee_proposal().unwrap();
+        assert_eq!(proposal.votes_for, to_yocto(30).into());
+        assert_eq!(proposal.votes_against, to_yocto(20).into());
+
+        // Shares are unlocked after the voting, fee is applied after the timelock.
+        context.block_timestamp = FEE_VOTING_PERIOD;
+        testing_env!(context.clone());
+        assert_eq!(pool.unlock_shares(), to_yocto(20).into());
+        assert_eq!(pool.get_balance(user.clone()), to_yocto(20).into());
+        context.block_timestamp = FEE_VOTING_PERIOD + FEE_TIMELOCK;
+        testing_env!(context.clone());
+        pool.execute_swap_fee();
+        assert_eq!(pool.get_swap_fee(), new_fee.into());
+        assert!(pool.get_fee_proposal().is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_PROPOSAL_FAILED")]
+    fn test_swap_fee_governance_quorum() {
+        let context = get_context(factory_account(), to_yocto(10), 0, false);
+        testing_env!(context.clone());
+        let mut pool = BPool::new();
+        bind(&mut pool, token1_account(), to_yocto(50), to_yocto(10));
+        bind(&mut pool, token2_account(), to_yocto(50), to_yocto(10));
+        pool.finalize();
+
+        // 20 shares out of 100 are below the quorum.
+        pool.propose_swap_fee(MAX_FEE.into());
+        pool.vote_swap_fee(true, to_yocto(20).into());
+        let mut context = get_context(factory_account(), to_yocto(10), 0, false);
+        context.block_timestamp = FEE_VOTING_PERIOD + FEE_TIMELOCK;
+        testing_env!(context.clone());
+        pool.execute_swap_fee();
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_TIMELOCK")]
+    fn test_swap_fee_governance_timelock() {
+        let context = get_context(factory_account(), to_yocto(10), 0, false);
+        testing_env!(context.clone());
+        let mut pool = BPool::new();
+        bind(&mut pool, token1_account(), to_yocto(50), to_yocto(10));
+        bind(&mut pool, token2_account(), to_yocto(50), to_yocto(10));
+        pool.finalize();
+
+        pool.propose_swap_fee(MAX_FEE.into());
+        pool.vote_swap_fee(true, to_yocto(100).into());
+        let mut context = get_context(factory_account(), to_yocto(10), 0, false);
+        context.block_timestamp = FEE_VOTING_PERIOD;
+        testing_env!(context.clone());
+        pool.execute_swap_fee();
     }
 }
@@ -0,0 +1,147 @@
+//! Smart pool rights on top of `BPool`: gradual weight updates for liquidity bootstrapping,
+//! same as `update_weights_gradually` and `poke_weights` of Balancer's configurable rights pool.
+
+use borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::{env, near_bindgen};
+use serde::Serialize;
+
+use crate::bconst::*;
+use crate::bnum::{badd, U256};
+use crate::*;
+
+/// Linear change of weights of all tokens between `start_time` and `end_time`.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct WeightUpdate {
+    start_time: u64,
+    end_time: u64,
+    start_weights: Vec<Weight>,
+    end_weights: Vec<Weight>,
+}
+
+/// Scheduled weight update as returned by `get_weight_update`, times are in nanoseconds.
+#[derive(Serialize)]
+pub struct WeightUpdateInfo {
+    pub start_time: U64,
+    pub end_time: U64,
+    pub start_weights: Vec<U128>,
+    pub end_weights: Vec<U128>,
+}
+
+impl WeightUpdate {
+    /// Weights interpolated for the given timestamp, `end_weights` once it's past `end_time`.
+    fn weights_at(&self, timestamp: u64) -> Vec<Weight> {
+        if timestamp >= self.end_time {
+            return self.end_weights.clone();
+        }
+        let elapsed = U256::from(timestamp.saturating_sub(self.start_time));
+        let duration = U256::from(self.end_time - self.start_time);
+        self.start_weights
+            .iter()
+            .zip(self.end_weights.iter())
+            .map(|(&start, &end)| {
+                if end >= start {
+                    start + (U256::from(end - start) * elapsed / duration).as_u128()
+                } else {
+                    start - (U256::from(start - end) * elapsed / duration).as_u128()
+                }
+            })
+            .collect()
+    }
+}
+
+#[near_bindgen]
+impl BPool {
+    /// Schedules linear change of weights from the current ones to `new_weights` between
+    /// `start_time` and `end_time` block timestamps. Weights are applied with `poke_weights`,
+    /// also after the pool is finalized.
+    pub fn update_weights_gradually(
+        &mut self,
+        new_weights: Vec<U128>,
+        start_time: U64,
+        end_time: U64,
+    ) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.controller,
+            "ERR_NOT_CONTROLLER"
+        );
+        assert_eq!(new_weights.len(), self.tokens.len(), "ERR_WEIGHTS_MISMATCH");
+        let start_time = std::cmp::max(start_time.into(), env::block_timestamp());
+        let end_time: u64 = end_time.into();
+        assert!(end_time > start_time, "ERR_BAD_TIMES");
+
+        let end_weights: Vec<Weight> = new_weights.into_iter().map(|w| w.into()).collect();
+        let mut total_weight = 0;
+        for &denorm in end_weights.iter() {
+            assert!(denorm >= MIN_WEIGHT, "ERR_MIN_WEIGHT");
+            assert!(denorm <= MAX_WEIGHT, "ERR_MAX_WEIGHT");
+            total_weight = badd(total_weight, denorm);
+        }
+        assert!(total_weight <= MAX_TOTAL_WEIGHT, "ERR_MAX_TOTAL_WEIGHT");
+
+        let start_weights = self
+            .tokens
+            .iter()
+            .map(|token| self.records.get(token).unwrap().denorm)
+            .collect();
+        self.weight_update = Some(WeightUpdate {
+            start_time,
+            end_time,
+            start_weights,
+            end_weights,
+        });
+    }
+
+    /// Applies weights of the scheduled update for the current block, can be called by anyone.
+    /// Interpolated weights stay within the bounds, as both start and end weights do.
+    pub fn poke_weights(&mut self) {
+        let update = self.weight_update.as_ref().expect("ERR_NO_WEIGHT_UPDATE");
+        let timestamp = env::block_timestamp();
+        assert!(timestamp >= update.start_time, "ERR_CANT_POKE_YET");
+        let weights = update.weights_at(timestamp);
+        if timestamp >= update.end_time {
+            self.weight_update = None;
+        }
+        for (token, denorm) in self.tokens.clone().iter().zip(weights) {
+            let mut record = self.records.get(token).unwrap();
+            self.set_denorm(&mut record, denorm);
+            self.records.insert(token, &record);
+        }
+        assert!(
+            self.total_weight <= MAX_TOTAL_WEIGHT,
+            "ERR_MAX_TOTAL_WEIGHT"
+        );
+    }
+
+    pub fn get_weight_update(&self) -> Option<WeightUpdateInfo> {
+        self.weight_update.as_ref().map(|update| WeightUpdateInfo {
+            start_time: update.start_time.into(),
+            end_time: update.end_time.into(),
+            start_weights: update.start_weights.iter().map(|&w| w.into()).collect(),
+            end_weights: update.end_weights.iter().map(|&w| w.into()).collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_weights_at() {
+        let update = WeightUpdate {
+            start_time: 100,
+            end_time: 200,
+            start_weights: vec![10 * BONE, 40 * BONE],
+            end_weights: vec![40 * BONE, 10 * BONE],
+        };
+        assert_eq!(update.weights_at(100), vec![10 * BONE, 40 * BONE]);
+        assert_eq!(
+            update.weights_at(125),
+            vec![17_500 * BONE / 1000, 32_500 * BONE / 1000]
+        );
+        assert_eq!(update.weights_at(200), vec![40 * BONE, 10 * BONE]);
+        assert_eq!(update.weights_at(1000), vec![40 * BONE, 10 * BONE]);
+    }
+}
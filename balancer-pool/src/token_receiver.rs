@@ -0,0 +1,129 @@
+//! Receiving NEP-141 tokens via `ft_transfer_call`, `msg` describes what to do with them.
+
+use near_sdk::json_types::U128;
+use near_sdk::{env, near_bindgen, AccountId};
+use serde::{Deserialize, Serialize};
+
+use crate::*;
+
+/// Action to perform with the received tokens, `msg` of `ft_transfer_call`.
+/// For example: `{"swap_exact_amount_in": {"token_out": "dai", "min_amount_out": "1", "max_price": "2"}}`.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Message {
+    /// Binds the token approved by the controller via `bind` with all received tokens
+    /// as it's balance, sent as `"bind"`.
+    Bind,
+    /// Controller adds received tokens to the balance of the bound token and sets it's weight.
+    Rebind { denorm: U128 },
+    /// Deposits received tokens to be used by `join_pool`, sent as `"deposit"`.
+    /// The sender must be registered with `register_deposits`.
+    Deposit,
+    /// Swaps all received tokens to at least `min_amount_out` of `token_out`.
+    SwapExactAmountIn {
+        token_out: AccountId,
+        min_amount_out: U128,
+        max_price: U128,
+    },
+    /// Swaps received tokens to exactly `token_amount_out` of `token_out`, the rest is refunded.
+    SwapExactAmountOut {
+        token_out: AccountId,
+        token_amount_out: U128,
+        max_price: U128,
+    },
+    /// Joins the pool with all received tokens for at least `min_pool_amount_out` shares.
+    JoinswapExternAmountIn { min_pool_amount_out: U128 },
+    /// Joins the pool for exactly `pool_amount_out` shares, unused tokens are refunded.
+    JoinswapPoolAmountOut { pool_amount_out: U128 },
+}
+
+#[near_bindgen]
+impl BPool {
+    /// Called by NEP-141 token contract after `ft_transfer_call` from `sender_id`.
+    /// Returns the amount of unused tokens that is refunded to the sender.
+    pub fn ft_on_transfer(&mut self, sender_id: AccountId, amount: U128, msg: String) -> U128 {
+        let token_in = env::predecessor_account_id();
+        let message = serde_json::from_str::<Message>(&msg).expect("ERR_MSG_WRONG_FORMAT");
+        match message {
+            Message::Bind => {
+                self.bind_received(&sender_id, &token_in, amount.into());
+                0.into()
+            }
+            Message::Rebind { denorm } => {
+                self.rebind_received(&sender_id, &token_in, amount.into(), denorm.into());
+                0.into()
+            }
+            Message::Deposit => {
+                assert!(self.is_bound(token_in.clone()), "ERR_NOT_BOUND");
+                assert!(
+                    self.deposits.get(&sender_id).is_some(),
+                    "ERR_NOT_REGISTERED"
+                );
+                self.internal_deposit(&sender_id, &token_in, amount.into());
+                0.into()
+            }
+            Message::SwapExactAmountIn {
+                token_out,
+                min_amount_out,
+                max_price,
+            } => {
+                let token_amount_out = self.swap_exact_amount_in(
+                    &token_in,
+                    amount.into(),
+                    &token_out,
+                    min_amount_out.into(),
+                    max_price.into(),
+                );
+                self.send_swap_output(
+                    &sender_id,
+                    &token_in,
+                    amount.into(),
+                    &token_out,
+                    token_amount_out,
+                );
+                0.into()
+            }
+            Message::SwapExactAmountOut {
+                token_out,
+                token_amount_out,
+                max_price,
+            } => {
+                let token_amount_in = self.swap_exact_amount_out(
+                    &token_in,
+                    amount.into(),
+                    &token_out,
+                    token_amount_out.into(),
+                    max_price.into(),
+                );
+                self.send_swap_output(
+                    &sender_id,
+                    &token_in,
+                    token_amount_in,
+                    &token_out,
+                    token_amount_out.into(),
+                );
+                (amount.0 - token_amount_in).into()
+            }
+            Message::JoinswapExternAmountIn {
+                min_pool_amount_out,
+            } => {
+                self.joinswap_extern_amount_in(
+                    &sender_id,
+                    &token_in,
+                    amount.into(),
+                    min_pool_amount_out.into(),
+                );
+                0.into()
+            }
+            Message::JoinswapPoolAmountOut { pool_amount_out } => {
+                let token_amount_in = self.joinswap_pool_amount_out(
+                    &sender_id,
+                    &token_in,
+                    amount.into(),
+                    pool_amount_out.into(),
+                );
+                (amount.0 - token_amount_in).into()
+            }
+        }
+    }
+}
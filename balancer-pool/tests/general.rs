@@ -1,103 +1,451 @@
-use near_sdk::AccountId;
+use std::process::Command;
+use std::sync::Once;
+
+use borsh::BorshSerialize;
 use near_sdk::json_types::U128;
-use near_test::test_user::{init_test_runtime, TestRuntime, to_yocto};
-use near_test::token::TokenContract;
-use serde_json::json;
+use near_sdk::AccountId;
+use near_sdk_sim::{init_simulator, to_yocto, ExecutionResult, UserAccount, DEFAULT_GAS};
+use serde_json::{json, Value};
+
+near_sdk_sim::lazy_static_include::lazy_static_include_bytes! {
+    TEST_TOKEN_WASM_BYTES => "../test-token/res/test_token.wasm",
+}
+
+/// Builds the pool with `build.sh` once per test run, so the tests never run a stale
+/// `res/balancer_pool.wasm`. Requires the `wasm32-unknown-unknown` target
+/// (`rustup target add wasm32-unknown-unknown`), then `cargo test` or `./test.sh` runs them.
+fn pool_wasm_bytes() -> Vec<u8> {
+    static BUILD: Once = Once::new();
+    BUILD.call_once(|| {
+        let status = Command::new("./build.sh")
+            .current_dir(env!("CARGO_MANIFEST_DIR"))
+            .status()
+            .expect("Failed to run build.sh");
+        assert!(status.success(), "Failed to build the pool");
+    });
+    std::fs::read(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/res/balancer_pool.wasm"
+    ))
+    .unwrap()
+}
 
 const WETH: &str = "weth";
 const MKR: &str = "mkr";
 const DAI: &str = "dai";
-const XXX: &str = "xxx";
 const POOL: &str = "pool";
+const FACTORY: &str = "factory";
 
-lazy_static::lazy_static! {
-    static ref TOKEN_WASM_BYTES: &'static [u8] = include_bytes!("../../test-token/res/test_token.wasm").as_ref();
-    static ref POOL_WASM_BYTES: &'static [u8] = include_bytes!("../res/balancer_pool.wasm").as_ref();
+/// Builds the factory with it's `build.sh` once per test run, same as the pool.
+fn factory_wasm_bytes() -> Vec<u8> {
+    static BUILD: Once = Once::new();
+    BUILD.call_once(|| {
+        let status = Command::new("./build.sh")
+            .current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/../balancer-factory"))
+            .status()
+            .expect("Failed to run build.sh");
+        assert!(status.success(), "Failed to build the factory");
+    });
+    std::fs::read(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/../balancer-factory/res/balancer_factory.wasm"
+    ))
+    .unwrap()
 }
 
-pub struct BPool {
-    contract_id: AccountId,
+/// Pool and test-token are built with other versions of near-sdk, so they are called via raw JSON.
+fn call(
+    user: &UserAccount,
+    contract_id: &str,
+    method: &str,
+    args: Value,
+    deposit: u128,
+) -> ExecutionResult {
+    user.call(
+        contract_id.to_string(),
+        method,
+        args.to_string().as_bytes(),
+        DEFAULT_GAS,
+        deposit,
+    )
 }
 
-impl BPool {
-    pub fn new(runtime: &mut TestRuntime, signer_id: &AccountId, contract_id: AccountId) -> Self {
-        let _ = runtime
-            .deploy(signer_id.clone(), contract_id.clone(), &POOL_WASM_BYTES, json!({}))
-            .unwrap();
-        Self { contract_id }
-    }
+fn view_u128(user: &UserAccount, contract_id: &str, method: &str, args: Value) -> u128 {
+    user.view(contract_id.to_string(), method, args.to_string().as_bytes())
+        .unwrap_json::<U128>()
+        .0
+}
 
-    pub fn getController(&self, runtime: &mut TestRuntime) -> AccountId {
-        runtime.view(self.contract_id.clone(), "getController", json!({}))
-            .as_str()
-            .unwrap()
-            .to_string()
+fn token_balance(user: &UserAccount, token_id: &str, account_id: &str) -> u128 {
+    view_u128(
+        user,
+        token_id,
+        "ft_balance_of",
+        json!({ "account_id": account_id }),
+    )
+}
+
+fn pool_balance(user: &UserAccount, token_id: &str) -> u128 {
+    view_u128(
+        user,
+        POOL,
+        "get_token_balance",
+        json!({ "token": token_id }),
+    )
+}
+
+fn pool_shares(user: &UserAccount, account_id: &AccountId) -> u128 {
+    view_u128(user, POOL, "get_balance", json!({ "owner_id": account_id }))
+}
+
+/// Sends tokens to the pool via `ft_transfer_call` with given message.
+fn transfer_call(user: &UserAccount, token_id: &str, amount: u128, msg: Value) {
+    call(
+        user,
+        token_id,
+        "ft_transfer_call",
+        json!({
+            "receiver_id": POOL,
+            "amount": U128(amount),
+            "msg": msg.to_string(),
+        }),
+        1,
+    )
+    .assert_success();
+}
+
+/// Deploys token with 1000000 minted to root and registers the pool on it.
+fn deploy_token(root: &UserAccount, token_id: &str) {
+    root.deploy(
+        &TEST_TOKEN_WASM_BYTES,
+        token_id.to_string(),
+        to_yocto("100"),
+    );
+    call(root, token_id, "new", json!({}), 0).assert_success();
+    call(
+        root,
+        token_id,
+        "mint",
+        json!({ "account_id": root.account_id, "amount": U128(to_yocto("1000000")) }),
+        0,
+    )
+    .assert_success();
+    call(
+        root,
+        token_id,
+        "ar_register",
+        json!({ "account_id": POOL }),
+        to_yocto("1"),
+    )
+    .assert_success();
+}
+
+/// Creates user registered on given tokens with given balances of them.
+fn create_user(root: &UserAccount, account_id: &str, balances: &[(&str, u128)]) -> UserAccount {
+    let user = root.create_user(account_id.to_string(), to_yocto("100"));
+    for (token_id, amount) in balances {
+        call(
+            &user,
+            token_id,
+            "ar_register",
+            json!({ "account_id": user.account_id }),
+            to_yocto("1"),
+        )
+        .assert_success();
+        if *amount > 0 {
+            call(
+                root,
+                token_id,
+                "ft_transfer",
+                json!({ "receiver_id": user.account_id, "amount": U128(*amount) }),
+                1,
+            )
+            .assert_success();
+        }
     }
+    user
+}
 
-    pub fn getNumTokens(&self, runtime: &mut TestRuntime) -> u64 {
-        runtime.view(self.contract_id.clone(), "getNumTokens", json!({}))
-            .as_u64()
-            .unwrap()
+/// Deploys 3 tokens and the pool, binds 50 WETH, 20 MKR and 10000 DAI with equal weights
+/// and finalizes the pool.
+fn setup_pool() -> UserAccount {
+    let root = init_simulator(None);
+    for token_id in &[WETH, MKR, DAI] {
+        deploy_token(&root, token_id);
     }
+    root.deploy(&pool_wasm_bytes(), POOL.to_string(), to_yocto("100"));
+    call(&root, POOL, "new", json!({}), 0).assert_success();
 
-    pub fn bind(&self, runtime: &mut TestRuntime, signer_id: &AccountId, token: AccountId, balance: &str, denorm: &str) {
-        let _ = runtime.call(signer_id.clone(), self.contract_id.clone(), "bind", json!({"token": token, "balance": U128::from(to_yocto(balance)), "denorm": U128::from(to_yocto(denorm))}), 0).unwrap();
+    for (token_id, balance) in &[(WETH, "50"), (MKR, "20"), (DAI, "10000")] {
+        call(
+            &root,
+            POOL,
+            "bind",
+            json!({ "token": token_id, "denorm": U128(to_yocto("5")) }),
+            0,
+        )
+        .assert_success();
+        transfer_call(&root, token_id, to_yocto(balance), json!("bind"));
     }
+    call(&root, POOL, "finalize", json!({}), 0).assert_success();
+    root
 }
 
-fn setup_multi_token_pool() -> (
-    TestRuntime,
-    BPool,
-    TokenContract,
-    TokenContract,
-    TokenContract,
-    TokenContract,
-) {
-    let mut runtime = init_test_runtime();
-    let root = "root".to_string();
-    let user1 = "user1".to_string();
-    let user2 = "user2".to_string();
-
-    let pool = BPool::new(&mut runtime, &root, POOL.to_string());
-
-    let weth = TokenContract::new(&mut runtime, &root, &TOKEN_WASM_BYTES, WETH.to_string(), &root, "50");
-    let mkr = TokenContract::new(&mut runtime, &root, &TOKEN_WASM_BYTES, MKR.to_string(), &root, "20");
-    let dai = TokenContract::new(
-        &mut runtime,
-        &root,
-        &TOKEN_WASM_BYTES,
-        DAI.to_string(),
+#[test]
+fn test_multi_token_pool() {
+    let root = setup_pool();
+    let info = root
+        .view(POOL.to_string(), "get_pool_info", b"{}")
+        .unwrap_json_value();
+    assert_eq!(info["tokens"], json!([WETH, MKR, DAI]));
+    assert_eq!(info["controller"], json!(root.account_id));
+    assert_eq!(info["finalized"], json!(true));
+    assert_eq!(pool_shares(&root, &root.account_id), to_yocto("100"));
+    assert_eq!(pool_balance(&root, WETH), to_yocto("50"));
+    assert_eq!(token_balance(&root, DAI, POOL), to_yocto("10000"));
+    assert_eq!(
+        token_balance(&root, WETH, &root.account_id),
+        to_yocto("999950")
+    );
+}
+
+#[test]
+fn test_join_swap_exit() {
+    let root = setup_pool();
+    let user = create_user(
         &root,
-        "10000",
+        "user1",
+        &[
+            (WETH, to_yocto("25")),
+            (MKR, to_yocto("4")),
+            (DAI, to_yocto("40000")),
+        ],
     );
-    let xxx = TokenContract::new(&mut runtime, &root, &TOKEN_WASM_BYTES, XXX.to_string(), &root, "10");
 
-    // User1 balances.
-    weth.mint(&mut runtime, &root, &user1, "25");
-    mkr.mint(&mut runtime, &root, &user1, "4");
-    dai.mint(&mut runtime, &root, &user1, "40000");
-    xxx.mint(&mut runtime, &root, &user1, "10");
+    // Join for 10% of the pool with deposited tokens.
+    call(&user, POOL, "register_deposits", json!({}), to_yocto("0.1")).assert_success();
+    for (token_id, amount) in &[(WETH, "5"), (MKR, "2"), (DAI, "1000")] {
+        transfer_call(&user, token_id, to_yocto(amount), json!("deposit"));
+    }
+    call(
+        &user,
+        POOL,
+        "join_pool",
+        json!({
+            "pool_amount_out": U128(to_yocto("10")),
+            "max_amounts_in": [U128(to_yocto("5")), U128(to_yocto("2")), U128(to_yocto("1000"))],
+        }),
+        0,
+    )
+    .assert_success();
+    assert_eq!(pool_shares(&root, &user.account_id), to_yocto("10"));
+    assert_eq!(pool_balance(&root, WETH), to_yocto("55"));
+    assert_eq!(pool_balance(&root, MKR), to_yocto("22"));
+    assert_eq!(pool_balance(&root, DAI), to_yocto("11000"));
+
+    // Swap 2.5 WETH to DAI.
+    transfer_call(
+        &user,
+        WETH,
+        to_yocto("2.5"),
+        json!({ "swap_exact_amount_in": {
+            "token_out": DAI,
+            "min_amount_out": U128(to_yocto("400")),
+            "max_price": U128(to_yocto("1")),
+        }}),
+    );
+    let dai_out = token_balance(&root, DAI, &user.account_id) - to_yocto("39000");
+    // Roughly 11000 * 2.5 / 57.5 minus the fee.
+    assert!(dai_out > to_yocto("478") && dai_out < to_yocto("478.3"));
+    assert_eq!(pool_balance(&root, WETH), to_yocto("57.5"));
+    assert_eq!(pool_balance(&root, DAI), to_yocto("11000") - dai_out);
+
+    // Exit with all shares.
+    call(
+        &user,
+        POOL,
+        "exit_pool",
+        json!({
+            "pool_amount_in": U128(to_yocto("10")),
+            "min_amounts_out": [U128(1), U128(1), U128(1)],
+        }),
+        0,
+    )
+    .assert_success();
+    assert_eq!(pool_shares(&root, &user.account_id), 0);
+
+    // Pool holds exactly what it accounts for and tokens are conserved.
+    for token_id in &[WETH, MKR, DAI] {
+        let in_pool = pool_balance(&root, token_id);
+        assert_eq!(token_balance(&root, token_id, POOL), in_pool);
+        assert_eq!(
+            token_balance(&root, token_id, &user.account_id)
+                + in_pool
+                + token_balance(&root, token_id, &root.account_id),
+            to_yocto("1000000")
+        );
+    }
+    // User got back 1/11 of the pool after the swap.
+    assert!(token_balance(&root, MKR, &user.account_id) < to_yocto("4"));
+    assert!(token_balance(&root, MKR, &user.account_id) > to_yocto("3.999"));
+}
 
-    // User2 balances.
-    weth.mint(&mut runtime, &root, &user2, "12.2222");
-    mkr.mint(&mut runtime, &root, &user2, "1.015333");
-    dai.mint(&mut runtime, &root, &user2, "0");
-    xxx.mint(&mut runtime, &root, &user2, "51");
+#[test]
+fn test_swap_limit_out_refunds() {
+    let root = setup_pool();
+    let user = create_user(&root, "user1", &[(WETH, to_yocto("10")), (DAI, 0)]);
 
-    (runtime, pool, weth, mkr, dai, xxx)
+    // 1 WETH can't buy 1000 DAI, tokens are refunded by the token contract.
+    call(
+        &user,
+        WETH,
+        "ft_transfer_call",
+        json!({
+            "receiver_id": POOL,
+            "amount": U128(to_yocto("1")),
+            "msg": json!({ "swap_exact_amount_in": {
+                "token_out": DAI,
+                "min_amount_out": U128(to_yocto("1000")),
+                "max_price": U128(to_yocto("1")),
+            }})
+            .to_string(),
+        }),
+        1,
+    );
+    assert_eq!(token_balance(&root, WETH, &user.account_id), to_yocto("10"));
+    assert_eq!(token_balance(&root, DAI, &user.account_id), 0);
+    assert_eq!(pool_balance(&root, WETH), to_yocto("50"));
+    assert_eq!(token_balance(&root, WETH, POOL), to_yocto("50"));
 }
 
 #[test]
-fn multi_token_pool() {
-    let (mut user, pool, weth, mkr, dai, xxx) = setup_multi_token_pool();
-    let root = "root".to_string();
-    assert_eq!(pool.getController(&mut user), root);
-    assert_eq!(pool.getNumTokens(&mut user), 0);
+fn test_swap_out_failed_credits_deposit() {
+    let root = setup_pool();
+    // User is not registered on DAI, so the swap output can't be sent.
+    let user = create_user(&root, "user1", &[(WETH, to_yocto("10"))]);
+    transfer_call(
+        &user,
+        WETH,
+        to_yocto("1"),
+        json!({ "swap_exact_amount_in": {
+            "token_out": DAI,
+            "min_amount_out": U128(1),
+            "max_price": U128(to_yocto("1")),
+        }}),
+    );
+    assert_eq!(pool_balance(&root, WETH), to_yocto("50"));
+    assert_eq!(pool_balance(&root, DAI), to_yocto("10000"));
+    let deposits = user
+        .view(
+            POOL.to_string(),
+            "get_deposits",
+            json!({ "account_id": user.account_id })
+                .to_string()
+                .as_bytes(),
+        )
+        .unwrap_json_value();
+    assert_eq!(deposits, json!({ WETH: U128(to_yocto("1")) }));
+
+    // Swapped tokens are kept as the deposit and can be withdrawn.
+    call(
+        &user,
+        POOL,
+        "withdraw",
+        json!({ "token": WETH, "amount": U128(to_yocto("1")) }),
+        0,
+    )
+    .assert_success();
+    assert_eq!(token_balance(&root, WETH, &user.account_id), to_yocto("10"));
+    assert_eq!(token_balance(&root, WETH, POOL), to_yocto("50"));
 }
 
 #[test]
-fn deposit_failure() {
-    let (mut user, pool, weth, mkr, dai, xxx) = setup_multi_token_pool();
-    let root = "root".to_string();
-    pool.bind(&mut user, &root, weth.contract_id, "100", "1");
+fn test_factory_collect() {
+    let root = init_simulator(None);
+    for token_id in &[WETH, DAI] {
+        deploy_token(&root, token_id);
+    }
+    let blabs = root.create_user("blabs".to_string(), to_yocto("100"));
+    root.deploy(&factory_wasm_bytes(), FACTORY.to_string(), to_yocto("100"));
+    root.call(
+        FACTORY.to_string(),
+        "new",
+        &(blabs.account_id.clone(), pool_wasm_bytes())
+            .try_to_vec()
+            .unwrap(),
+        DEFAULT_GAS,
+        0,
+    )
+    .assert_success();
+    call(
+        &root,
+        FACTORY,
+        "new_bpool",
+        json!({ "name": "pool" }),
+        to_yocto("50"),
+    )
+    .assert_success();
+
+    let pool_id = format!("{}.{}", POOL, FACTORY);
+    let info = root
+        .view(pool_id.clone(), "get_pool_info", b"{}")
+        .unwrap_json_value();
+    assert_eq!(info["controller"], json!(root.account_id));
+    for (token_id, balance) in &[(WETH, "50"), (DAI, "10000")] {
+        call(
+            &root,
+            token_id,
+            "ar_register",
+            json!({ "account_id": pool_id }),
+            to_yocto("1"),
+        )
+        .assert_success();
+        call(
+            &root,
+            &pool_id,
+            "bind",
+            json!({ "token": token_id, "denorm": U128(to_yocto("5")) }),
+            0,
+        )
+        .assert_success();
+        call(
+            &root,
+            token_id,
+            "ft_transfer_call",
+            json!({
+                "receiver_id": pool_id,
+                "amount": U128(to_yocto(balance)),
+                "msg": json!("bind").to_string(),
+            }),
+            1,
+        )
+        .assert_success();
+    }
+    call(&root, &pool_id, "finalize", json!({}), 0).assert_success();
+
+    // Exit fee is zero, so shares are given to the factory directly.
+    call(
+        &root,
+        &pool_id,
+        "transfer",
+        json!({ "new_owner_id": FACTORY, "amount": U128(to_yocto("10")) }),
+        to_yocto("1"),
+    )
+    .assert_success();
+    let shares = |account_id: &str| {
+        view_u128(
+            &root,
+            &pool_id,
+            "get_balance",
+            json!({ "owner_id": account_id }),
+        )
+    };
+    assert_eq!(shares(&blabs.account_id), 0);
+
+    // Blabs never held shares, the factory pays for it's account on the pool.
+    let collected =
+        call(&blabs, FACTORY, "collect", json!({ "pool_id": pool_id }), 0).unwrap_json::<U128>();
+    assert_eq!(collected.0, to_yocto("10"));
+    assert_eq!(shares(&blabs.account_id), to_yocto("10"));
+    assert_eq!(shares(FACTORY), 0);
 }
@@ -1,12 +1,20 @@
 mod math;
+mod token_receiver;
 
+use near_contract_standards::fungible_token::resolver::FungibleTokenResolver;
 use near_contract_standards::fungible_token::{
     FungibleToken, FungibleTokenCore, FungibleTokenMetadata, FungibleTokenMetadataProvider,
 };
 use near_contract_standards::storage_manager::{AccountStorageBalance, StorageManager};
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::json_types::{ValidAccountId, U128};
-use near_sdk::{env, near_bindgen, Balance, PanicOnDefault, Promise};
+use near_sdk::json_types::{ValidAccountId, U128, U64};
+use near_sdk::{
+    env, ext_contract, near_bindgen, serde_json, AccountId, Balance, Gas, PanicOnDefault, Promise,
+    PromiseResult,
+};
+
+const NO_DEPOSIT: Balance = 0;
+const GAS_FOR_RESOLVE_BURN: Gas = 10_000_000_000_000;
 
 #[near_bindgen]
 #[derive(BorshSerialize, BorshDeserialize, PanicOnDefault)]
@@ -31,39 +39,145 @@ impl Contract {
         };
         this.token
             .internal_register_account(&env::predecessor_account_id());
+        // Holds tokens sent with `ft_transfer_call` until they are burned.
+        this.token
+            .internal_register_account(&env::current_account_id());
         this.token
             .internal_deposit(&env::predecessor_account_id(), initial_amount.into());
         this
     }
 
+    /// Mints tokens to `account_id` for the attached deposit.
+    /// If nothing or less than `min_amount_out` would be minted or the block timestamp is past
+    /// `deadline` (in nanoseconds), the deposit is refunded to the caller and 0 returned.
     #[payable]
-    pub fn mint(&mut self, account_id: ValidAccountId) -> U128 {
+    pub fn mint(
+        &mut self,
+        account_id: ValidAccountId,
+        min_amount_out: U128,
+        deadline: Option<U64>,
+    ) -> U128 {
         let deposit = env::attached_deposit();
-        let amount = math::calc_purchase_amount(
+        let amount = self.calc_mint_amount(deposit);
+        if !is_before_deadline(deadline) || amount == 0 || amount < min_amount_out.0 {
+            env::log(format!("Refund {} of deposit: limits are not met", deposit).as_bytes());
+            Promise::new(env::predecessor_account_id()).transfer(deposit);
+            return 0.into();
+        }
+        self.reserve_balance += deposit;
+        self.token.internal_deposit(account_id.as_ref(), amount);
+        amount.into()
+    }
+
+    /// Burns `amount` of caller's tokens and sends the reserve returned for them to the caller.
+    /// Fails if less than `min_amount_out` would be returned or the block timestamp is past `deadline`.
+    pub fn burn(&mut self, amount: U128, min_amount_out: U128, deadline: Option<U64>) -> Promise {
+        assert!(is_before_deadline(deadline), "ERR_DEADLINE_PASSED");
+        let return_amount = self.calc_burn_return(amount.into());
+        assert!(return_amount >= min_amount_out.0, "ERR_MIN_AMOUNT_OUT");
+        let account_id = env::predecessor_account_id();
+        self.internal_burn(
+            &account_id,
+            amount.into(),
+            return_amount,
+            account_id.clone(),
+        )
+    }
+
+    /// Callback after sending the reserve for burned tokens. If the transfer failed, restores
+    /// the reserve and the tokens of `account_id`. Returns the amount of restored tokens.
+    pub fn on_burn(&mut self, account_id: AccountId, amount: U128, return_amount: U128) -> U128 {
+        assert_self();
+        if is_promise_success() {
+            return 0.into();
+        }
+        self.reserve_balance += return_amount.0;
+        self.token.internal_deposit(&account_id, amount.into());
+        env::log(format!("Transfer failed, restored {} to {}", amount.0, account_id).as_bytes());
+        amount
+    }
+
+    /// Returns how many tokens `mint` would mint for the `deposit` now.
+    pub fn get_mint_amount(&self, deposit: U128) -> U128 {
+        self.calc_mint_amount(deposit.into()).into()
+    }
+
+    /// Returns how much reserve `burn` would return for `amount` of tokens now.
+    pub fn get_burn_return(&self, amount: U128) -> U128 {
+        self.calc_burn_return(amount.into()).into()
+    }
+}
+
+impl Contract {
+    /// Burns `amount` of `account_id` tokens and sends `return_amount` of the reserve to
+    /// `beneficiary_id`, both are restored by `on_burn` if the transfer fails.
+    fn internal_burn(
+        &mut self,
+        account_id: &AccountId,
+        amount: Balance,
+        return_amount: Balance,
+        beneficiary_id: AccountId,
+    ) -> Promise {
+        self.reserve_balance -= return_amount;
+        self.token.internal_withdraw(account_id, amount);
+        Promise::new(beneficiary_id)
+            .transfer(return_amount)
+            .then(ext_self::on_burn(
+                account_id.clone(),
+                amount.into(),
+                return_amount.into(),
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_RESOLVE_BURN,
+            ))
+    }
+
+    fn calc_mint_amount(&self, deposit: Balance) -> Balance {
+        math::calc_purchase_amount(
             self.ft_total_supply().0,
             self.reserve_balance,
             self.reserve_ratio,
             deposit,
-        );
-        self.reserve_balance += deposit;
-        self.token.internal_deposit(account_id.as_ref(), amount);
-        amount.into()
+        )
     }
 
-    pub fn burn(&mut self, amount: U128) -> Promise {
-        let return_amount = math::calc_sale_amount(
+    fn calc_burn_return(&self, amount: Balance) -> Balance {
+        math::calc_sale_amount(
             self.ft_total_supply().0,
             self.reserve_balance,
             self.reserve_ratio,
-            amount.into(),
-        );
-        self.reserve_balance -= return_amount;
-        self.token
-            .internal_withdraw(&env::predecessor_account_id(), amount.into());
-        Promise::new(env::predecessor_account_id()).transfer(return_amount)
+            amount,
+        )
+    }
+}
+
+fn is_before_deadline(deadline: Option<U64>) -> bool {
+    deadline.map_or(true, |deadline| env::block_timestamp() <= deadline.0)
+}
+
+/// Asserts that the method was called by the contract itself.
+fn assert_self() {
+    assert_eq!(
+        env::predecessor_account_id(),
+        env::current_account_id(),
+        "ERR_NOT_ALLOWED"
+    );
+}
+
+/// Returns true if the promise this callback is attached to succeeded.
+fn is_promise_success() -> bool {
+    assert_eq!(env::promise_results_count(), 1, "ERR_PROMISE_RESULTS");
+    match env::promise_result(0) {
+        PromiseResult::Successful(_) => true,
+        _ => false,
     }
 }
 
+#[ext_contract(ext_self)]
+trait ExtSelf {
+    fn on_burn(&mut self, account_id: AccountId, amount: U128, return_amount: U128) -> U128;
+}
+
 #[near_bindgen]
 impl FungibleTokenCore for Contract {
     #[payable]
@@ -91,6 +205,21 @@ impl FungibleTokenCore for Contract {
     }
 }
 
+#[near_bindgen]
+impl FungibleTokenResolver for Contract {
+    /// Refunds unused tokens of `ft_transfer_call`, including sales via `ft_on_transfer`.
+    fn ft_resolve_transfer(
+        &mut self,
+        sender_id: ValidAccountId,
+        receiver_id: ValidAccountId,
+        amount: U128,
+    ) -> U128 {
+        assert_self();
+        self.token
+            .ft_resolve_transfer(sender_id, receiver_id, amount)
+    }
+}
+
 #[near_bindgen]
 impl StorageManager for Contract {
     #[payable]
@@ -121,9 +250,10 @@ impl FungibleTokenMetadataProvider for Contract {
 
 #[cfg(test)]
 mod tests {
-    use near_sdk::test_utils::{accounts, VMContextBuilder};
-    use near_sdk::{env, testing_env, MockedBlockchain};
+    use near_sdk::test_utils::{accounts, testing_env_with_promise_results, VMContextBuilder};
+    use near_sdk::{env, testing_env, MockedBlockchain, PromiseOrValue};
 
+    use super::token_receiver::FungibleTokenReceiver;
     use super::*;
 
     const ONE_NEAR: u128 = 1_000_000_000_000_000_000_000_000;
@@ -132,6 +262,7 @@ mod tests {
     fn test_basics() {
         let mut context = VMContextBuilder::new();
         testing_env!(context
+            .current_account_id(accounts(5))
             .predecessor_account_id(accounts(3))
             .account_balance(1000 * env::storage_byte_cost())
             .storage_usage(1000)
@@ -144,14 +275,191 @@ mod tests {
             .build());
         contract.storage_deposit(Some(accounts(0)));
         testing_env!(context.attached_deposit(ONE_NEAR).build());
-        let minted_amount = contract.mint(accounts(0));
+        assert_eq!(
+            contract.get_mint_amount(ONE_NEAR.into()),
+            414213562373095047387475.into()
+        );
+        let minted_amount = contract.mint(accounts(0), 0.into(), None);
         assert_eq!(
             contract.ft_balance_of(accounts(0)),
-            414213562373095139835904.into()
+            414213562373095047387475.into()
+        );
+        let rb = contract.reserve_balance;
+        let burn_return = contract.get_burn_return(minted_amount);
+        contract.burn(minted_amount, burn_return, None);
+        assert_eq!(rb - contract.reserve_balance, burn_return.0);
+        // After burning, the balance subtracted is slightly less than what was deposited.
+        assert!(rb - contract.reserve_balance <= ONE_NEAR);
+        assert!(rb - contract.reserve_balance > ONE_NEAR - 10u128.pow(10));
+    }
+
+    /// Contract with 1e24 tokens owned by `accounts(3)` and 1e24N in reserve, `accounts(0)` registered.
+    fn setup_contract() -> (VMContextBuilder, Contract) {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context
+            .current_account_id(accounts(5))
+            .predecessor_account_id(accounts(3))
+            .account_balance(1000 * env::storage_byte_cost())
+            .storage_usage(1000)
+            .attached_deposit(ONE_NEAR)
+            .build());
+        let mut contract = Contract::new(ONE_NEAR.into(), 500_000);
+        testing_env!(context
+            .attached_deposit(125 * env::storage_byte_cost())
+            .build());
+        contract.storage_deposit(Some(accounts(0)));
+        (context, contract)
+    }
+
+    #[test]
+    fn test_mint_limits_refund() {
+        let (mut context, mut contract) = setup_contract();
+        let rb = contract.reserve_balance;
+        testing_env!(context
+            .attached_deposit(ONE_NEAR)
+            .block_timestamp(100)
+            .build());
+        let expected = contract.get_mint_amount(ONE_NEAR.into());
+        assert_eq!(
+            contract.mint(accounts(0), (expected.0 + 1).into(), None),
+            0.into()
+        );
+        assert_eq!(
+            contract.mint(accounts(0), expected, Some(99.into())),
+            0.into()
+        );
+        assert_eq!(contract.ft_balance_of(accounts(0)), 0.into());
+        assert_eq!(contract.reserve_balance, rb);
+        assert_eq!(
+            contract.mint(accounts(0), expected, Some(100.into())),
+            expected
         );
+        assert_eq!(contract.reserve_balance, rb + ONE_NEAR);
+    }
+
+    #[test]
+    fn test_mint_zero_amount_refund() {
+        let (mut context, mut contract) = setup_contract();
         let rb = contract.reserve_balance;
-        contract.burn(minted_amount);
-        // After burning, the balance subtracted is around what was deposited.
-        assert!(rb - contract.reserve_balance < ONE_NEAR + 10u128.pow(10));
+        // 1 yoctoNEAR is too little to mint anything, so it's refunded instead of kept.
+        testing_env!(context.attached_deposit(1).build());
+        assert_eq!(contract.get_mint_amount(1.into()), 0.into());
+        assert_eq!(contract.mint(accounts(0), 0.into(), None), 0.into());
+        assert_eq!(contract.reserve_balance, rb);
+        assert_eq!(contract.ft_balance_of(accounts(0)), 0.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_MIN_AMOUNT_OUT")]
+    fn test_burn_min_amount_out() {
+        let (mut context, mut contract) = setup_contract();
+        testing_env!(context.attached_deposit(0).build());
+        let burn_return = contract.get_burn_return(ONE_NEAR.into());
+        assert_eq!(burn_return, contract.reserve_balance.into());
+        contract.burn(ONE_NEAR.into(), (burn_return.0 + 1).into(), None);
+    }
+
+    #[test]
+    fn test_sell_via_transfer_call() {
+        let (mut context, mut contract) = setup_contract();
+        // Tokens are already moved to the contract by `ft_transfer_call`.
+        contract
+            .token
+            .internal_withdraw(&accounts(3).into(), ONE_NEAR / 2);
+        contract
+            .token
+            .internal_deposit(&accounts(5).into(), ONE_NEAR / 2);
+        testing_env!(context
+            .attached_deposit(0)
+            .predecessor_account_id(accounts(5))
+            .build());
+        let rb = contract.reserve_balance;
+        let expected = contract.get_burn_return((ONE_NEAR / 2).into());
+        let msg = format!("{{\"min_near_out\": \"{}\"}}", expected.0 + 1);
+        match contract.ft_on_transfer(accounts(3), (ONE_NEAR / 2).into(), msg) {
+            PromiseOrValue::Value(refund) => assert_eq!(refund.0, ONE_NEAR / 2),
+            _ => panic!("Tokens must be refunded"),
+        }
+        assert_eq!(contract.reserve_balance, rb);
+
+        let msg = format!(
+            "{{\"min_near_out\": \"{}\", \"beneficiary\": \"{}\"}}",
+            expected.0,
+            accounts(1).as_ref()
+        );
+        contract.ft_on_transfer(accounts(3), (ONE_NEAR / 2).into(), msg);
+        assert_eq!(contract.reserve_balance, rb - expected.0);
+        assert_eq!(contract.ft_balance_of(accounts(5)), 0.into());
+        assert_eq!(contract.ft_total_supply(), (ONE_NEAR / 2).into());
+    }
+
+    #[test]
+    fn test_burn_transfer_failed() {
+        let (mut context, mut contract) = setup_contract();
+        testing_env!(context.attached_deposit(0).build());
+        let rb = contract.reserve_balance;
+        let burn_return = contract.get_burn_return((ONE_NEAR / 2).into());
+        contract.burn((ONE_NEAR / 2).into(), burn_return, None);
+        assert_eq!(contract.reserve_balance, rb - burn_return.0);
+        assert_eq!(contract.ft_total_supply(), (ONE_NEAR / 2).into());
+
+        // Sending the reserve failed, burned tokens and the reserve are restored.
+        testing_env_with_promise_results(
+            context.predecessor_account_id(accounts(5)).build(),
+            PromiseResult::Failed,
+        );
+        assert_eq!(
+            contract.on_burn(accounts(3).into(), (ONE_NEAR / 2).into(), burn_return),
+            (ONE_NEAR / 2).into()
+        );
+        assert_eq!(contract.reserve_balance, rb);
+        assert_eq!(contract.ft_total_supply(), ONE_NEAR.into());
+        assert_eq!(contract.ft_balance_of(accounts(3)), ONE_NEAR.into());
+    }
+
+    #[test]
+    fn test_sell_via_transfer_call_failed() {
+        let (mut context, mut contract) = setup_contract();
+        contract
+            .token
+            .internal_withdraw(&accounts(3).into(), ONE_NEAR / 2);
+        contract
+            .token
+            .internal_deposit(&accounts(5).into(), ONE_NEAR / 2);
+        testing_env!(context
+            .attached_deposit(0)
+            .predecessor_account_id(accounts(5))
+            .build());
+        let rb = contract.reserve_balance;
+        let expected = contract.get_burn_return((ONE_NEAR / 2).into());
+        let msg = format!("{{\"min_near_out\": \"{}\"}}", expected.0);
+        contract.ft_on_transfer(accounts(3), (ONE_NEAR / 2).into(), msg);
+        assert_eq!(contract.reserve_balance, rb - expected.0);
+
+        // Sending the reserve failed, so `on_burn` restores the tokens to this contract and
+        // returns all of them as unused.
+        testing_env_with_promise_results(context.build(), PromiseResult::Failed);
+        let unused = contract.on_burn(accounts(5).into(), (ONE_NEAR / 2).into(), expected);
+        assert_eq!(unused, (ONE_NEAR / 2).into());
+        assert_eq!(contract.reserve_balance, rb);
+        assert_eq!(contract.ft_total_supply(), ONE_NEAR.into());
+
+        // `ft_resolve_transfer` refunds them to the sender.
+        testing_env_with_promise_results(
+            context.build(),
+            PromiseResult::Successful(format!("\"{}\"", unused.0).into_bytes()),
+        );
+        let used = contract.ft_resolve_transfer(accounts(3), accounts(5), (ONE_NEAR / 2).into());
+        assert_eq!(used, 0.into());
+        assert_eq!(contract.ft_balance_of(accounts(3)), ONE_NEAR.into());
+        assert_eq!(contract.ft_balance_of(accounts(5)), 0.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_DEADLINE_PASSED")]
+    fn test_burn_deadline() {
+        let (mut context, mut contract) = setup_contract();
+        testing_env!(context.attached_deposit(0).block_timestamp(100).build());
+        contract.burn(1_000.into(), 0.into(), Some(99.into()));
     }
 }
@@ -1,10 +1,99 @@
+//! Bonding curve math in deterministic fixed point arithmetic with `ONE` = 10^27 as one.
+//! Powers with fractional exponents are computed as `exp(exponent * ln(base))`, where `ln` and
+//! `exp` are series approximations over 256-bit integers.
+//!
+//! Error bounds: every step of `ln` and `exp` rounds down, which gives at most few units of
+//! `ONE` of absolute error for `ln` and relative error below 10^-24 for `exp` with arguments up
+//! to `MAX_EXP`. Multiplying `ln` by the exponent of at most `MAX_RESERVE_RATIO` keeps the error
+//! of the power below 10^-20 relative. Powers are shifted by `1 / POW_ERROR_DIVISOR` + 1 unit
+//! (10^-18 relative) against the trader, so both purchases and sales are rounded in favor of
+//! the reserve and buying and then selling never returns more than was deposited.
+//!
+//! Limits: `exp` takes arguments up to `MAX_EXP`, which bounds purchases to
+//! ln(1 + deposit_amount / reserve_balance) * reserve_ratio / MAX_RESERVE_RATIO <= 100. With
+//! `Balance` inputs this logarithm is below 89, so the practical limit is the minted amount:
+//! `supply * power` must fit 256 bits and the result must fit `Balance`. Purchases over these
+//! limits panic with `ERR_DEPOSIT_TOO_LARGE`, e.g. depositing 10^6 NEAR to a reserve of
+//! 1 yoctoNEAR at a high reserve ratio.
+
 use near_sdk::Balance;
+use uint::construct_uint;
+
+construct_uint! {
+    /// 256-bit unsigned integer.
+    pub struct U256(4);
+}
 
 const MAX_RESERVE_RATIO: u32 = 1_000_000;
 
+/// Fixed point one.
+const ONE: u128 = 1_000_000_000_000_000_000_000_000_000;
+
+/// ln(2) rounded down to 27 decimals.
+const LN2: u128 = 693_147_180_559_945_309_417_232_121;
+
+/// Maximum argument of `exp`, e^100 in fixed point still fits 256 bits.
+const MAX_EXP: u128 = 100 * ONE;
+
+/// Powers are adjusted by 1 / POW_ERROR_DIVISOR of their value to cover approximation error.
+const POW_ERROR_DIVISOR: u128 = 1_000_000_000_000_000_000;
+
+/// Natural logarithm of fixed point `x` >= `ONE`, rounded down.
+/// `x = m * 2^k` with `m` in [1, 2), ln(m) = 2 * atanh((m - 1) / (m + 1)) converges fast for it.
+fn ln(x: U256) -> U256 {
+    let one = U256::from(ONE);
+    assert!(x >= one, "ERR_LN_INPUT");
+    let mut k: usize = 0;
+    while (x >> (k + 1)) >= one {
+        k += 1;
+    }
+    let m = x >> k;
+    let z = (m - one) * one / (m + one);
+    let z2 = z * z / one;
+    let mut term = z;
+    let mut sum = z;
+    let mut n = 1u64;
+    while !term.is_zero() {
+        term = term * z2 / one;
+        sum += term / U256::from(2 * n + 1);
+        n += 1;
+    }
+    U256::from(LN2) * U256::from(k) + sum * U256::from(2)
+}
+
+/// Exponent of fixed point `a` <= `MAX_EXP`, rounded down.
+/// `a = k * ln(2) + r` with `r` in [0, ln(2)), e^a = 2^k * e^r with Taylor series for e^r.
+fn exp(a: U256) -> U256 {
+    let one = U256::from(ONE);
+    assert!(a <= U256::from(MAX_EXP), "ERR_EXP_OVERFLOW");
+    let ln2 = U256::from(LN2);
+    let k = a / ln2;
+    let r = a - k * ln2;
+    let mut term = one;
+    let mut sum = one;
+    let mut i = 1u64;
+    while !term.is_zero() {
+        term = term * r / (one * U256::from(i));
+        sum += term;
+        i += 1;
+    }
+    sum << k.as_usize()
+}
+
+fn to_balance(value: U256) -> Balance {
+    assert!(value <= U256::from(Balance::MAX), "ERR_OVERFLOW");
+    value.as_u128()
+}
+
+/// Margin covering the approximation error of the power `value`.
+fn pow_error(value: U256) -> U256 {
+    value / U256::from(POW_ERROR_DIVISOR) + U256::one()
+}
+
 /// Given continues token supply, reserve balance and reserve ratio, return how much tokens will be purchased with given `deposit_amount`.
 /// Formula:
 ///     return = supply * ((1 + deposit_amount / reserve_balance) ^ (reserve_ratio / MAX_RESERVE_RATIO) - 1)
+/// Rounded down.
 pub(crate) fn calc_purchase_amount(
     supply: Balance,
     reserve_balance: Balance,
@@ -15,20 +104,31 @@ pub(crate) fn calc_purchase_amount(
         supply > 0 && reserve_balance > 0 && reserve_ratio > 0 && deposit_amount > 0,
         "ERR_INPUT_ZERO"
     );
+    let supply = U256::from(supply);
     if reserve_ratio == MAX_RESERVE_RATIO {
-        return supply * deposit_amount / reserve_balance;
+        return to_balance(supply * U256::from(deposit_amount) / U256::from(reserve_balance));
     }
 
-    (supply as f64
-        * ((1f64 + deposit_amount as f64 / reserve_balance as f64)
-            .powf(reserve_ratio as f64 / MAX_RESERVE_RATIO as f64)
-            - 1f64))
-        .ceil() as u128
+    let one = U256::from(ONE);
+    let base = one * (U256::from(reserve_balance) + U256::from(deposit_amount))
+        / U256::from(reserve_balance);
+    let arg = ln(base) * U256::from(reserve_ratio) / U256::from(MAX_RESERVE_RATIO);
+    assert!(arg <= U256::from(MAX_EXP), "ERR_DEPOSIT_TOO_LARGE");
+    let power = exp(arg);
+    let power = power.saturating_sub(pow_error(power));
+    if power <= one {
+        return 0;
+    }
+    match supply.checked_mul(power - one).map(|value| value / one) {
+        Some(amount) if amount <= U256::from(Balance::MAX) => amount.as_u128(),
+        _ => panic!("ERR_DEPOSIT_TOO_LARGE"),
+    }
 }
 
 /// Given total supply, reserve balance and reserve ratio, calculate how much reserve to return for given number of tokens to sell.
 /// Formula:
 ///     return = reserve_balance * (1 - (1 - sell_amount / supply) ^ (1 / (reserve_ration / MAX_RESERVE_RATIO)))
+/// Rounded down.
 pub(crate) fn calc_sale_amount(
     supply: Balance,
     reserve_balance: Balance,
@@ -39,14 +139,163 @@ pub(crate) fn calc_sale_amount(
         supply > 0 && reserve_balance > 0 && reserve_ratio > 0 && sell_amount > 0,
         "ERR_INPUT_ZERO"
     );
+    assert!(sell_amount <= supply, "ERR_SELL_EXCEEDS_SUPPLY");
     if sell_amount == supply {
         return reserve_balance;
-    } else if reserve_ratio == MAX_RESERVE_RATIO {
-        return reserve_balance * sell_amount / supply;
-    }
-    (reserve_balance as f64
-        * (1f64
-            - (1f64 - sell_amount as f64 / supply as f64)
-                .powf(MAX_RESERVE_RATIO as f64 / reserve_ratio as f64)))
-    .floor() as u128
+    }
+    let reserve = U256::from(reserve_balance);
+    if reserve_ratio == MAX_RESERVE_RATIO {
+        return (reserve * U256::from(sell_amount) / U256::from(supply)).as_u128();
+    }
+
+    // (1 - sell_amount / supply) ^ e = 1 / (supply / (supply - sell_amount)) ^ e, rounded up.
+    let one = U256::from(ONE);
+    let base = one * U256::from(supply) / U256::from(supply - sell_amount);
+    let arg = ln(base) * U256::from(MAX_RESERVE_RATIO) / U256::from(reserve_ratio);
+    let power = if arg >= U256::from(MAX_EXP) {
+        U256::zero()
+    } else {
+        let denominator = exp(arg);
+        (one * one + denominator - U256::one()) / denominator
+    };
+    let power = power + pow_error(power);
+    if power >= one {
+        return 0;
+    }
+    (reserve * (one - power) / one).as_u128()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ONE_NEAR: Balance = 1_000_000_000_000_000_000_000_000;
+
+    /// Deterministic xorshift64* generator, the crate has no `rand` dependency.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+        }
+
+        /// Random amount in [1, 10^digits] with `digits` uniform in [0, max_digits],
+        /// so small and large amounts are equally likely.
+        fn amount(&mut self, max_digits: u32) -> Balance {
+            let digits = (self.next() % (max_digits as u64 + 1)) as u32;
+            let value = ((self.next() as u128) << 64) | self.next() as u128;
+            value % 10u128.pow(digits) + 1
+        }
+    }
+
+    #[test]
+    fn test_ln_exp() {
+        assert_eq!(ln(U256::from(ONE)), U256::zero());
+        assert_eq!(exp(U256::zero()), U256::from(ONE));
+        // ln(2) and e are within few units.
+        let ln2 = ln(U256::from(2 * ONE)).as_u128();
+        assert!(LN2 - ln2 <= 2);
+        let e = exp(U256::from(ONE)).as_u128();
+        assert!(2_718_281_828_459_045_235_360_287_471 - e <= 100);
+        // exp(ln(x)) returns x back with relative error far below 10^-18.
+        let x = U256::from(123_456_789u128) * U256::from(ONE);
+        let y = exp(ln(x));
+        assert!(y <= x && x - y < x / U256::from(POW_ERROR_DIVISOR));
+    }
+
+    #[test]
+    fn test_purchase_and_sale() {
+        // Square root curve: 1 NEAR to the reserve of 1 NEAR mints supply * (sqrt(2) - 1).
+        assert_eq!(
+            calc_purchase_amount(ONE_NEAR, ONE_NEAR, 500_000, ONE_NEAR),
+            414_213_562_373_095_047_387_475
+        );
+        // Selling half of the supply on square curve returns 3/4 of the reserve.
+        let returned = calc_sale_amount(2 * ONE_NEAR, ONE_NEAR, 500_000, ONE_NEAR);
+        assert!(returned <= 750_000_000_000_000_000_000_000);
+        assert!(750_000_000_000_000_000_000_000 - returned < 10u128.pow(7));
+        // Linear curve is exact and selling everything returns the whole reserve.
+        assert_eq!(
+            calc_purchase_amount(ONE_NEAR, ONE_NEAR, MAX_RESERVE_RATIO, ONE_NEAR),
+            ONE_NEAR
+        );
+        assert_eq!(calc_sale_amount(ONE_NEAR, 7, 1, ONE_NEAR), 7);
+    }
+
+    #[test]
+    fn test_buy_then_sell_never_profits() {
+        let supplies = [1, 1_000, 10u128.pow(18), ONE_NEAR];
+        let reserves = [10u128.pow(12), ONE_NEAR, 10u128.pow(30)];
+        let ratios = [
+            1,
+            10,
+            1_000,
+            100_000,
+            333_333,
+            500_000,
+            999_999,
+            MAX_RESERVE_RATIO,
+        ];
+        let deposits = [1, 999, 10u128.pow(18), ONE_NEAR];
+        for &supply in supplies.iter() {
+            for &reserve in reserves.iter() {
+                for &ratio in ratios.iter() {
+                    for &deposit in deposits.iter() {
+                        let minted = calc_purchase_amount(supply, reserve, ratio, deposit);
+                        if minted == 0 {
+                            continue;
+                        }
+                        let returned =
+                            calc_sale_amount(supply + minted, reserve + deposit, ratio, minted);
+                        assert!(
+                            returned <= deposit,
+                            "{} {} {} {}: {} > {}",
+                            supply,
+                            reserve,
+                            ratio,
+                            deposit,
+                            returned,
+                            deposit
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_buy_then_sell_never_profits_random() {
+        let mut rng = Rng(0x5eed_1234_abcd_0001);
+        for _ in 0..1_000 {
+            let supply = rng.amount(30);
+            let reserve = rng.amount(30);
+            // Deposits up to 1000 times the reserve keep the minted amount within `Balance`.
+            let deposit = rng.amount(30).min(reserve.saturating_mul(1_000));
+            let ratio = (rng.next() % MAX_RESERVE_RATIO as u64) as u32 + 1;
+            let minted = calc_purchase_amount(supply, reserve, ratio, deposit);
+            if minted == 0 {
+                continue;
+            }
+            let returned = calc_sale_amount(supply + minted, reserve + deposit, ratio, minted);
+            assert!(
+                returned <= deposit,
+                "{} {} {} {}: {} > {}",
+                supply,
+                reserve,
+                ratio,
+                deposit,
+                returned,
+                deposit
+            );
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_DEPOSIT_TOO_LARGE")]
+    fn test_purchase_deposit_too_large() {
+        calc_purchase_amount(ONE_NEAR, 1, 999_999, 1_000_000 * ONE_NEAR);
+    }
 }
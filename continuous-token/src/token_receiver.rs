@@ -0,0 +1,57 @@
+//! Selling tokens by sending them to this contract via `ft_transfer_call`.
+
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::PromiseOrValue;
+
+use crate::*;
+
+/// Message passed to `ft_transfer_call` to sell all sent tokens for at least `min_near_out`,
+/// sent to `beneficiary` or to the sender. For example: `{"min_near_out": "100"}`.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SellMessage {
+    pub min_near_out: U128,
+    pub beneficiary: Option<ValidAccountId>,
+}
+
+pub trait FungibleTokenReceiver {
+    /// Called after `ft_transfer_call` of `amount` tokens from `sender_id` to this contract.
+    /// Returns the amount of unused tokens that are refunded to the sender.
+    fn ft_on_transfer(
+        &mut self,
+        sender_id: ValidAccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128>;
+}
+
+#[near_bindgen]
+impl FungibleTokenReceiver for Contract {
+    /// Burns received tokens and sends the returned reserve. `msg` must be a `SellMessage`.
+    /// Tokens are refunded if less than `min_near_out` would be returned or the transfer fails.
+    fn ft_on_transfer(
+        &mut self,
+        sender_id: ValidAccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        assert_eq!(
+            env::predecessor_account_id(),
+            env::current_account_id(),
+            "ERR_WRONG_TOKEN"
+        );
+        let message = serde_json::from_str::<SellMessage>(&msg).expect("ERR_MSG_MALFORMED");
+        let return_amount = self.calc_burn_return(amount.into());
+        if return_amount < message.min_near_out.0 {
+            env::log(format!("ERR_MIN_AMOUNT_OUT: refunding {}", amount.0).as_bytes());
+            return PromiseOrValue::Value(amount);
+        }
+        let beneficiary_id = message.beneficiary.unwrap_or(sender_id);
+        PromiseOrValue::Promise(self.internal_burn(
+            &env::current_account_id(),
+            amount.into(),
+            return_amount,
+            beneficiary_id.into(),
+        ))
+    }
+}
@@ -0,0 +1,6 @@
+#!/bin/bash
+set -e
+
+mkdir -p res
+RUSTFLAGS='-C link-arg=-s' cargo +stable build --target wasm32-unknown-unknown --release
+cp target/wasm32-unknown-unknown/release/uniswap_factory.wasm ./res/
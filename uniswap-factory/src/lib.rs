@@ -0,0 +1,189 @@
+use near_lib::promises::{assert_self, is_promise_success};
+use near_lib::upgrade::Ownable;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::json_types::U128;
+use near_sdk::serde_json::json;
+use near_sdk::{env, ext_contract, near_bindgen, AccountId, Balance, Promise};
+
+#[global_allocator]
+static ALLOC: near_sdk::wee_alloc::WeeAlloc<'_> = near_sdk::wee_alloc::WeeAlloc::INIT;
+
+const CODE_KEY: &[u8; 4] = b"code";
+
+const NO_DEPOSIT: Balance = 0;
+
+/// This gas spent on the call & account creation, the rest goes to the `new` call.
+const CREATE_CALL_GAS: u64 = 5_000_000_000_000;
+
+/// Gas for the callback that records or releases the exchange.
+const ON_CREATE_CALL_GAS: u64 = 10_000_000_000_000;
+
+/// Factory of uniswap exchanges: deploys one exchange per token and keeps the registry of them.
+#[near_bindgen]
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct UniswapFactory {
+    owner: AccountId,
+    /// Token account -> exchange account.
+    exchanges: LookupMap<AccountId, AccountId>,
+    /// Exchange account -> token account.
+    tokens: LookupMap<AccountId, AccountId>,
+}
+
+impl Default for UniswapFactory {
+    fn default() -> Self {
+        env::panic(b"UniswapFactory should be initialized before usage")
+    }
+}
+
+#[near_bindgen]
+impl UniswapFactory {
+    #[init]
+    pub fn new(#[serializer(borsh)] owner: AccountId, #[serializer(borsh)] code: Vec<u8>) -> Self {
+        assert!(!env::state_exists(), "The contract is already initialized");
+        env::storage_write(CODE_KEY, &code);
+        Self {
+            owner,
+            exchanges: LookupMap::new(b"e".to_vec()),
+            tokens: LookupMap::new(b"t".to_vec()),
+        }
+    }
+
+    /// Creates exchange for given token at `name.<factory>` account with given fee.
+    /// Attached deposit is passed to the exchange to cover it's storage.
+    /// Owner of the factory becomes owner of the exchange's protocol fee.
+    #[payable]
+    pub fn create_exchange(
+        &mut self,
+        name: AccountId,
+        token_account_id: AccountId,
+        fee: u32,
+    ) -> Promise {
+        assert!(
+            env::is_valid_account_id(token_account_id.as_bytes()),
+            "ERR_INVALID_TOKEN"
+        );
+        assert!(
+            !self.exchanges.contains_key(&token_account_id),
+            "ERR_EXCHANGE_EXISTS"
+        );
+        let exchange_id = format!("{}.{}", name, env::current_account_id());
+        // Reserve the token right away, released in the callback if creation fails.
+        self.exchanges.insert(&token_account_id, &exchange_id);
+        self.tokens.insert(&exchange_id, &token_account_id);
+        let code = env::storage_read(CODE_KEY).expect("Code must be present");
+        Promise::new(exchange_id.clone())
+            .create_account()
+            .deploy_contract(code)
+            .function_call(
+                b"new".to_vec(),
+                json!({
+                    "token_account_id": token_account_id,
+                    "fee": fee,
+                    "owner_id": self.owner,
+                })
+                .to_string()
+                .into_bytes(),
+                env::attached_deposit(),
+                env::prepaid_gas() - CREATE_CALL_GAS - ON_CREATE_CALL_GAS,
+            )
+            .then(ext_self::on_create_exchange(
+                token_account_id,
+                exchange_id,
+                env::predecessor_account_id(),
+                env::attached_deposit().into(),
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                ON_CREATE_CALL_GAS,
+            ))
+    }
+
+    /// Callback after exchange creation. If it failed, removes the exchange from the registry
+    /// and refunds the deposit to the creator.
+    pub fn on_create_exchange(
+        &mut self,
+        token_account_id: AccountId,
+        exchange_id: AccountId,
+        creator_id: AccountId,
+        deposit: U128,
+    ) -> bool {
+        assert_self();
+        if is_promise_success() {
+            return true;
+        }
+        self.exchanges.remove(&token_account_id);
+        self.tokens.remove(&exchange_id);
+        if deposit.0 > 0 {
+            Promise::new(creator_id).transfer(deposit.0);
+        }
+        false
+    }
+
+    /// Returns exchange for given token if it exists.
+    pub fn get_exchange(&self, token_account_id: AccountId) -> Option<AccountId> {
+        self.exchanges.get(&token_account_id)
+    }
+
+    /// Returns token of given exchange if it was created by this factory.
+    pub fn get_token(&self, exchange_id: AccountId) -> Option<AccountId> {
+        self.tokens.get(&exchange_id)
+    }
+
+    pub fn upgrade(&self, #[serializer(borsh)] code: Vec<u8>) {
+        self.assert_owner();
+        env::storage_write(CODE_KEY, &code);
+    }
+}
+
+impl Ownable for UniswapFactory {
+    fn get_owner(&self) -> AccountId {
+        self.owner.clone()
+    }
+    fn set_owner(&mut self, owner: AccountId) {
+        self.assert_owner();
+        self.owner = owner;
+    }
+}
+
+#[ext_contract(ext_self)]
+pub trait ExtSelf {
+    fn on_create_exchange(
+        &mut self,
+        token_account_id: AccountId,
+        exchange_id: AccountId,
+        creator_id: AccountId,
+        deposit: U128,
+    ) -> bool;
+}
+
+#[cfg(test)]
+mod tests {
+    use near_lib::context::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, MockedBlockchain};
+
+    use super::*;
+
+    #[test]
+    fn test_basics() {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .finish());
+        let mut factory = UniswapFactory::new(accounts(0), vec![].into());
+        factory.create_exchange("dai".to_string(), accounts(1), 3);
+        let exchange_id = format!("dai.{}", accounts(0));
+        assert_eq!(factory.get_exchange(accounts(1)), Some(exchange_id.clone()));
+        assert_eq!(factory.get_token(exchange_id), Some(accounts(1)));
+        assert_eq!(factory.get_exchange(accounts(2)), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_EXCHANGE_EXISTS")]
+    fn test_duplicate_exchange() {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .finish());
+        let mut factory = UniswapFactory::new(accounts(0), vec![].into());
+        factory.create_exchange("dai".to_string(), accounts(1), 3);
+        factory.create_exchange("dai2".to_string(), accounts(1), 3);
+    }
+}
@@ -1,66 +1,183 @@
 use std::convert::TryInto;
 
+use near_contract_standards::fungible_token::resolver::FungibleTokenResolver;
+use near_contract_standards::fungible_token::{
+    FungibleToken, FungibleTokenCore, FungibleTokenMetadata, FungibleTokenMetadataProvider,
+};
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::collections::LookupMap;
 use near_sdk::json_types::{ValidAccountId, U128};
 use near_sdk::{
     env, ext_contract, near_bindgen, serde_json, AccountId, Balance, Gas, PanicOnDefault, Promise,
+    PromiseOrValue, PromiseResult, StorageUsage,
 };
 use uint::construct_uint;
 
+pub use crate::views::{CumulativePrices, ProtocolFee, Reserves};
+
+mod storage_impl;
+mod token_receiver;
+mod views;
+
 const FEE_DIVISOR: u32 = 1_000;
+/// Protocol fee is a fraction of the fee growth, in `1 / PROTOCOL_FEE_DIVISOR` units.
+const PROTOCOL_FEE_DIVISOR: u32 = 1_000;
 const NO_DEPOSIT: Balance = 0;
-const GAS_FOR_SWAP: Gas = 10_000_000_000_000;
+/// NEP-141 requires exactly 1 yoctoNEAR attached to `ft_transfer`.
+const ONE_YOCTO: Balance = 1;
+const GAS_FOR_FT_TRANSFER: Gas = 10_000_000_000_000;
+const GAS_FOR_RESOLVE_TRANSFER: Gas = 10_000_000_000_000;
+/// Gas for `swap_near_to_token` on another exchange, including it's transfer and callback.
+const GAS_FOR_SWAP: Gas = 40_000_000_000_000;
+/// Gas for looking up the other exchange in the factory registry.
+const GAS_FOR_GET_TOKEN: Gas = 5_000_000_000_000;
+/// Gas for the callback that continues token to token swap after the registry lookup.
+const GAS_FOR_ON_GET_TOKEN: Gas = GAS_FOR_SWAP + 2 * GAS_FOR_RESOLVE_TRANSFER;
 
 construct_uint! {
     /// 256-bit unsigned integer.
     pub struct U256(4);
 }
 
+impl BorshSerialize for U256 {
+    fn serialize<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        self.0.serialize(writer)
+    }
+}
+
+impl BorshDeserialize for U256 {
+    fn deserialize(buf: &mut &[u8]) -> std::io::Result<Self> {
+        Ok(U256(<[u64; 4]>::deserialize(buf)?))
+    }
+}
+
 #[near_bindgen]
 #[derive(BorshSerialize, BorshDeserialize, PanicOnDefault)]
-struct Contract {
+pub struct Contract {
+    /// Account of the factory that created this exchange.
+    factory_id: AccountId,
+    /// Account that can configure the protocol fee.
+    owner_id: AccountId,
+    /// Account receiving the protocol fee as shares. Protocol fee is off if not set.
+    fee_to: Option<AccountId>,
+    /// Fraction of the fee growth that goes to `fee_to`, in `PROTOCOL_FEE_DIVISOR` units.
+    protocol_fee: u32,
+    /// Product of reserves after the last liquidity event, zero if protocol fee is off.
+    k_last: U256,
     /// Account for the token.
     token_account_id: AccountId,
     /// Fee.
     fee: u32,
     /// Balances of NEAR that were deposited but not consumed yet.
     near_balances: LookupMap<AccountId, Balance>,
-    /// Shares of the pool by liquidity providers.
-    shares: LookupMap<AccountId, Balance>,
-    shares_total_supply: Balance,
+    /// Shares of the pool by liquidity providers, transferable as NEP-141 token.
+    shares: FungibleToken,
+    /// Number of `remove_liquidity` calls waiting for the token transfer, per account.
+    pending_removals: LookupMap<AccountId, u32>,
+    /// Storage paid by each registered account: it's shares, pending NEAR balance and
+    /// pending removals entries.
+    account_storage_usage: StorageUsage,
     /// How much NEAR this contract has.
     near_amount: Balance,
     /// How much token this contract has.
     token_amount: Balance,
+    /// Sum of NEAR prices in tokens (as Q128 fixed point) multiplied by nanoseconds they lasted.
+    near_price_cumulative: U256,
+    /// Sum of token prices in NEAR (as Q128 fixed point) multiplied by nanoseconds they lasted.
+    token_price_cumulative: U256,
+    /// Block timestamp of the last reserves update.
+    block_timestamp_last: u64,
 }
 
 #[near_bindgen]
 impl Contract {
+    /// Initializes exchange for given token. `owner_id` defaults to the caller.
     #[init]
-    pub fn new(token_account_id: ValidAccountId, fee: u32) -> Self {
+    pub fn new(
+        token_account_id: ValidAccountId,
+        fee: u32,
+        owner_id: Option<ValidAccountId>,
+    ) -> Self {
         assert!(!env::state_exists(), "ERR_CONTRACT_IS_INITIALIZED");
         assert!(fee < FEE_DIVISOR, "ERR_FEE_TOO_LARGE");
-        Self {
+        let mut this = Self {
+            factory_id: env::predecessor_account_id(),
+            owner_id: owner_id
+                .map(|a| a.into())
+                .unwrap_or_else(env::predecessor_account_id),
+            fee_to: None,
+            protocol_fee: 0,
+            k_last: U256::zero(),
             token_account_id: token_account_id.into(),
             fee,
             near_balances: LookupMap::new(b"t".to_vec()),
-            shares: LookupMap::new(b"s".to_vec()),
-            shares_total_supply: 0,
+            shares: FungibleToken::new(),
+            pending_removals: LookupMap::new(b"r".to_vec()),
+            account_storage_usage: 0,
             near_amount: 0,
             token_amount: 0,
-        }
+            near_price_cumulative: U256::zero(),
+            token_price_cumulative: U256::zero(),
+            block_timestamp_last: env::block_timestamp(),
+        };
+        this.measure_account_storage_usage();
+        this
     }
 
     /// Adds liquidity to this pool.
+    /// First step: deposit NEAR, that is paired with tokens sent via `ft_transfer_call` with
+    /// `liquidity` message. If tokens were not sent, NEAR can be withdrawn via `withdraw_near`.
+    /// Caller must be registered via `storage_deposit`.
     #[payable]
     pub fn add_liquidity(&mut self) {
+        let account_id = env::predecessor_account_id();
+        self.assert_registered(&account_id);
         let amount = env::attached_deposit();
-        add_to_collection(
-            &mut self.near_balances,
-            &env::predecessor_account_id(),
-            amount,
+        add_to_collection(&mut self.near_balances, &account_id, amount);
+    }
+
+    /// Withdraws NEAR deposited via `add_liquidity` that was not paired with tokens yet.
+    pub fn withdraw_near(&mut self) -> Promise {
+        let account_id = env::predecessor_account_id();
+        let amount = self
+            .near_balances
+            .remove(&account_id)
+            .expect("ERR_NO_NEAR_BALANCE");
+        Promise::new(account_id).transfer(amount)
+    }
+
+    /// Returns NEAR deposited via `add_liquidity` that was not paired with tokens yet.
+    pub fn get_near_balance(&self, account_id: ValidAccountId) -> U128 {
+        self.near_balances
+            .get(account_id.as_ref())
+            .unwrap_or_default()
+            .into()
+    }
+
+    /// Sets account receiving the protocol fee and it's fraction of the fee growth.
+    /// Fee accrued with the previous settings is minted first. `None` turns the protocol fee off.
+    pub fn set_protocol_fee(&mut self, fee_to: Option<ValidAccountId>, protocol_fee: u32) {
+        self.assert_owner();
+        assert!(
+            protocol_fee <= PROTOCOL_FEE_DIVISOR,
+            "ERR_PROTOCOL_FEE_TOO_LARGE"
         );
+        if let Some(fee_to) = fee_to.as_ref() {
+            assert!(
+                self.shares.accounts.contains_key(fee_to.as_ref()),
+                "ERR_FEE_TO_NOT_REGISTERED"
+            );
+        }
+        self.mint_protocol_fee();
+        self.fee_to = fee_to.map(|a| a.into());
+        self.protocol_fee = protocol_fee;
+        self.update_k_last();
+    }
+
+    /// Transfers ownership of the exchange configuration.
+    pub fn set_owner(&mut self, owner_id: ValidAccountId) {
+        self.assert_owner();
+        self.owner_id = owner_id.into();
     }
 
     pub fn remove_liquidity(
@@ -69,38 +186,79 @@ impl Contract {
         min_near_amount: U128,
         min_token_amount: U128,
     ) -> Promise {
+        self.mint_protocol_fee();
         let shares_amount: u128 = shares.into();
-        assert!(shares_amount > 0 && self.shares_total_supply > 0);
+        let shares_total_supply = self.shares_total_supply();
+        assert!(shares_amount > 0 && shares_total_supply > 0);
         let near_amount = (U256::from(shares_amount) * U256::from(self.near_amount)
-            / U256::from(self.shares_total_supply))
+            / U256::from(shares_total_supply))
         .as_u128();
         let token_amount = (U256::from(shares_amount) * U256::from(self.token_amount)
-            / U256::from(self.shares_total_supply))
+            / U256::from(shares_total_supply))
         .as_u128();
         assert!(near_amount >= min_near_amount.into() && token_amount >= min_token_amount.into());
         let account_id = env::predecessor_account_id();
-        let prev_amount = self.shares.get(&account_id).unwrap_or(0);
-        assert!(prev_amount >= shares_amount, "ERR_NOT_ENOUGH_SHARES");
-        // TODO: don't allow to withdraw and leave less than required for storage.
-        if prev_amount == shares_amount {
-            self.shares.remove(&account_id);
-        } else {
+        assert!(
             self.shares
-                .insert(&account_id, &(prev_amount - shares_amount));
-        }
-        self.shares_total_supply -= shares_amount;
+                .ft_balance_of(account_id.clone().try_into().unwrap())
+                .0
+                >= shares_amount,
+            "ERR_NOT_ENOUGH_SHARES"
+        );
+        self.shares.internal_withdraw(&account_id, shares_amount);
+        self.update_price_accumulators();
         self.near_amount -= near_amount;
         self.token_amount -= token_amount;
-        Promise::new(account_id.clone()).transfer(near_amount);
-        // TODO: handle error on transfer.
+        self.update_k_last();
+        self.internal_update_pending_removals(&account_id, true);
+        // Transfer tokens first, NEAR is sent only if the token transfer succeeded.
         ext_fungible_token::ft_transfer(
-            account_id.try_into().unwrap(),
+            account_id.clone().try_into().unwrap(),
             U128(token_amount),
             None,
             &self.token_account_id,
-            NO_DEPOSIT,
-            env::prepaid_gas() - GAS_FOR_SWAP,
+            ONE_YOCTO,
+            GAS_FOR_FT_TRANSFER,
         )
+        .then(ext_self::on_remove_liquidity(
+            account_id,
+            U128(shares_amount),
+            U128(near_amount),
+            U128(token_amount),
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_RESOLVE_TRANSFER,
+        ))
+    }
+
+    /// Callback after transferring tokens on `remove_liquidity`.
+    /// On success sends NEAR to the liquidity provider, otherwise returns shares and reserves back.
+    pub fn on_remove_liquidity(
+        &mut self,
+        account_id: AccountId,
+        shares: U128,
+        near_amount: U128,
+        token_amount: U128,
+    ) {
+        assert_self();
+        self.internal_update_pending_removals(&account_id, false);
+        if is_promise_success() {
+            Promise::new(account_id).transfer(near_amount.into());
+        } else {
+            env::log(
+                format!(
+                    "Failed to transfer tokens to {}, restoring shares",
+                    account_id
+                )
+                .as_bytes(),
+            );
+            self.mint_protocol_fee();
+            self.shares.internal_deposit(&account_id, shares.into());
+            self.update_price_accumulators();
+            self.near_amount += near_amount.0;
+            self.token_amount += token_amount.0;
+            self.update_k_last();
+        }
     }
 
     /// Pricing between two reserves given input amount.
@@ -143,120 +301,458 @@ impl Contract {
         self.get_output_price(amount, self.token_amount, self.near_amount)
     }
 
+    /// Swaps attached NEAR to tokens, that are sent to `recipient` or to the caller if not given.
     #[payable]
-    pub fn swap_near_to_token(&mut self, min_amount: Balance) -> Balance {
+    pub fn swap_near_to_token(
+        &mut self,
+        min_amount: Balance,
+        recipient: Option<ValidAccountId>,
+    ) -> Promise {
         let payed_amount = env::attached_deposit();
         let tokens_bought = self.get_input_price(payed_amount, self.near_amount, self.token_amount);
         assert!(tokens_bought >= min_amount, "ERR_MIN_AMOUNT");
+        self.update_price_accumulators();
         self.near_amount += payed_amount;
         self.token_amount -= tokens_bought;
+        self.internal_send_bought_tokens(recipient, payed_amount, tokens_bought)
+    }
+
+    /// Swaps attached NEAR to exactly `amount_out` tokens, that are sent to `recipient` or to
+    /// the caller if not given. Attached NEAR that was not used is refunded.
+    #[payable]
+    pub fn swap_near_to_token_exact_out(
+        &mut self,
+        amount_out: U128,
+        recipient: Option<ValidAccountId>,
+    ) -> Promise {
+        let max_near_amount = env::attached_deposit();
+        let tokens_bought: Balance = amount_out.into();
+        // Round up the price in favor of the pool.
+        let near_sold =
+            self.get_output_price(tokens_bought, self.near_amount, self.token_amount) + 1;
+        assert!(near_sold <= max_near_amount, "ERR_MAX_AMOUNT");
+        self.update_price_accumulators();
+        self.near_amount += near_sold;
+        self.token_amount -= tokens_bought;
+        if max_near_amount > near_sold {
+            Promise::new(env::predecessor_account_id()).transfer(max_near_amount - near_sold);
+        }
+        self.internal_send_bought_tokens(recipient, near_sold, tokens_bought)
+    }
+
+    /// Sends tokens bought for `near_amount` to the recipient, refunds NEAR to the caller if transfer fails.
+    fn internal_send_bought_tokens(
+        &mut self,
+        recipient: Option<ValidAccountId>,
+        near_amount: Balance,
+        tokens_bought: Balance,
+    ) -> Promise {
+        let account_id = env::predecessor_account_id();
+        let recipient = recipient
+            .map(|a| a.into())
+            .unwrap_or_else(|| account_id.clone());
         ext_fungible_token::ft_transfer(
-            env::predecessor_account_id().try_into().unwrap(),
+            recipient.try_into().unwrap(),
             U128(tokens_bought),
             None,
             &self.token_account_id,
+            ONE_YOCTO,
+            GAS_FOR_FT_TRANSFER,
+        )
+        .then(ext_self::on_swap_near_to_token(
+            account_id,
+            U128(near_amount),
+            U128(tokens_bought),
+            &env::current_account_id(),
             NO_DEPOSIT,
-            env::prepaid_gas() - GAS_FOR_SWAP,
-        );
-        // TODO: handle failure to transfer (e.g. no storage).
-        tokens_bought
+            GAS_FOR_RESOLVE_TRANSFER,
+        ))
     }
 
-    fn swap_token_to_near(
+    /// Callback after transferring bought tokens on `swap_near_to_token`.
+    /// Returns amount of tokens bought. If transfer failed (e.g. receiver is not registered),
+    /// rolls back the swap, refunds attached NEAR and returns 0.
+    pub fn on_swap_near_to_token(
+        &mut self,
+        account_id: AccountId,
+        near_amount: U128,
+        token_amount: U128,
+    ) -> U128 {
+        assert_self();
+        if is_promise_success() {
+            return token_amount;
+        }
+        env::log(format!("Failed to transfer tokens to {}, refunding", account_id).as_bytes());
+        self.update_price_accumulators();
+        self.near_amount -= near_amount.0;
+        self.token_amount += token_amount.0;
+        Promise::new(account_id).transfer(near_amount.into());
+        U128(0)
+    }
+
+    /// Updates reserves for selling `token_amount` and returns how much NEAR was bought.
+    fn internal_swap_token_to_near(
         &mut self,
-        sender_id: &AccountId,
         token_amount: Balance,
         min_near_amount: Balance,
-    ) -> Promise {
+    ) -> Balance {
         let near_bought = self.get_input_price(token_amount, self.token_amount, self.near_amount);
         assert!(near_bought >= min_near_amount, "ERR_MIN_AMOUNT");
+        self.update_price_accumulators();
         self.near_amount -= near_bought;
-        self.token_amount -= token_amount;
-        Promise::new(sender_id.clone()).transfer(near_bought)
+        self.token_amount += token_amount;
+        near_bought
+    }
+
+    fn swap_token_to_near(
+        &mut self,
+        recipient: &AccountId,
+        token_amount: Balance,
+        min_near_amount: Balance,
+    ) -> Promise {
+        let near_bought = self.internal_swap_token_to_near(token_amount, min_near_amount);
+        Promise::new(recipient.clone()).transfer(near_bought)
     }
 
-    fn finish_add_liquidity(&mut self, sender_id: &AccountId, amount: U128) -> U128 {
+    /// Swaps up to `max_token_amount` tokens to exactly `near_amount_out` NEAR.
+    /// Returns amount of tokens that were not used.
+    fn swap_token_to_near_exact_out(
+        &mut self,
+        recipient: &AccountId,
+        max_token_amount: Balance,
+        near_amount_out: Balance,
+    ) -> Balance {
+        // Round up the price in favor of the pool.
+        let tokens_sold =
+            self.get_output_price(near_amount_out, self.token_amount, self.near_amount) + 1;
+        assert!(tokens_sold <= max_token_amount, "ERR_MAX_AMOUNT");
+        self.update_price_accumulators();
+        self.near_amount -= near_amount_out;
+        self.token_amount += tokens_sold;
+        Promise::new(recipient.clone()).transfer(near_amount_out);
+        max_token_amount - tokens_sold
+    }
+
+    /// Swaps tokens to NEAR and then NEAR to token of `exchange_id` via that exchange.
+    /// The other exchange must be registered in the same factory, which is checked first with
+    /// `get_token` on the factory. Bought tokens go to `recipient`.
+    fn swap_token_to_token(
+        &mut self,
+        recipient: &AccountId,
+        token_amount: Balance,
+        exchange_id: &AccountId,
+        min_amount_out: Balance,
+    ) -> Promise {
+        assert_ne!(
+            exchange_id,
+            &env::current_account_id(),
+            "ERR_WRONG_EXCHANGE"
+        );
+        ext_factory::get_token(
+            exchange_id.clone(),
+            &self.factory_id,
+            NO_DEPOSIT,
+            GAS_FOR_GET_TOKEN,
+        )
+        .then(ext_self::on_get_exchange_token(
+            recipient.clone(),
+            U128(token_amount),
+            exchange_id.clone(),
+            U128(min_amount_out),
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_ON_GET_TOKEN,
+        ))
+    }
+
+    /// Callback with the token of `exchange_id` from the factory registry. If the factory created
+    /// that exchange, continues token to token swap, otherwise refunds the tokens.
+    pub fn on_get_exchange_token(
+        &mut self,
+        recipient: AccountId,
+        token_amount: U128,
+        exchange_id: AccountId,
+        min_amount_out: U128,
+    ) -> PromiseOrValue<U128> {
+        assert_self();
+        let exchange_token = match env::promise_result(0) {
+            PromiseResult::Successful(value) => {
+                serde_json::from_slice::<Option<AccountId>>(&value).unwrap_or(None)
+            }
+            _ => None,
+        };
+        if exchange_token.is_none() {
+            env::log(format!("ERR_WRONG_EXCHANGE: refunding {}", token_amount.0).as_bytes());
+            return PromiseOrValue::Value(token_amount);
+        }
+        let near_bought = self.internal_swap_token_to_near(token_amount.into(), 0);
+        PromiseOrValue::Promise(
+            ext_exchange::swap_near_to_token(
+                min_amount_out.into(),
+                Some(recipient.try_into().unwrap()),
+                &exchange_id,
+                near_bought,
+                GAS_FOR_SWAP,
+            )
+            .then(ext_self::on_swap_token_to_token(
+                token_amount,
+                U128(near_bought),
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_RESOLVE_TRANSFER,
+            )),
+        )
+    }
+
+    /// Callback after buying tokens on the other exchange in token to token swap.
+    /// Returns amount of tokens to refund to the sender: if the other exchange failed,
+    /// it returned the NEAR back and this swap is rolled back.
+    pub fn on_swap_token_to_token(&mut self, token_amount: U128, near_amount: U128) -> U128 {
+        assert_self();
+        let tokens_bought = match env::promise_result(0) {
+            PromiseResult::Successful(value) => serde_json::from_slice::<U128>(&value)
+                .map(|amount| amount.0)
+                .unwrap_or(0),
+            _ => 0,
+        };
+        if tokens_bought > 0 {
+            return U128(0);
+        }
+        env::log(b"Failed to swap on the other exchange, refunding");
+        self.update_price_accumulators();
+        self.near_amount += near_amount.0;
+        self.token_amount -= token_amount.0;
+        token_amount
+    }
+
+    /// Second step of adding liquidity: pairs received tokens with NEAR deposited by `sender_id`.
+    /// Returns amount of tokens that were not used and must be refunded.
+    /// Panics if not enough tokens or less than `min_liquidity` shares would be minted,
+    /// in which case tokens are refunded and NEAR stays in `near_balances`.
+    fn finish_add_liquidity(
+        &mut self,
+        sender_id: &AccountId,
+        amount: Balance,
+        min_liquidity: Balance,
+    ) -> Balance {
         let near_amount = self
             .near_balances
             .remove(&sender_id)
             .expect("ERR_NOT_ADD_LIQUIDITY");
-        if self.shares_total_supply > 0 {
-            let expected_token_amount = near_amount * self.token_amount / self.near_amount;
-            assert!(
-                expected_token_amount <= amount.into(),
-                "ERR_NOT_ENOUGH_TOKEN"
-            );
-            let liquidity_minted = near_amount * self.shares_total_supply / self.near_amount;
-            add_to_collection(&mut self.shares, sender_id, liquidity_minted);
-            self.shares_total_supply += liquidity_minted;
+        self.update_price_accumulators();
+        self.mint_protocol_fee();
+        let shares_total_supply = self.shares_total_supply();
+        let refund = if shares_total_supply > 0 {
+            // Round up expected tokens in favor of existing liquidity providers.
+            let expected_token_amount = (U256::from(near_amount) * U256::from(self.token_amount)
+                / U256::from(self.near_amount))
+            .as_u128()
+                + 1;
+            assert!(expected_token_amount <= amount, "ERR_NOT_ENOUGH_TOKEN");
+            let liquidity_minted = (U256::from(near_amount) * U256::from(shares_total_supply)
+                / U256::from(self.near_amount))
+            .as_u128();
+            assert!(liquidity_minted >= min_liquidity, "ERR_MIN_LIQUIDITY");
+            self.shares.internal_deposit(sender_id, liquidity_minted);
             self.near_amount += near_amount;
             self.token_amount += expected_token_amount;
-            expected_token_amount.into()
+            amount - expected_token_amount
         } else {
-            self.shares_total_supply = near_amount;
+            assert!(near_amount >= min_liquidity, "ERR_MIN_LIQUIDITY");
             self.near_amount = near_amount;
-            self.token_amount = amount.into();
-            add_to_collection(&mut self.shares, sender_id, near_amount);
-            amount
+            self.token_amount = amount;
+            self.shares.internal_deposit(sender_id, near_amount);
+            0
+        };
+        self.update_k_last();
+        refund
+    }
+
+    fn assert_owner(&self) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.owner_id,
+            "ERR_NOT_OWNER"
+        );
+    }
+
+    fn protocol_fee_on(&self) -> bool {
+        self.fee_to.is_some() && self.protocol_fee > 0
+    }
+
+    /// Returns shares to mint to `fee_to` for the growth of `sqrt(k)` since the last
+    /// liquidity event, same as `_mintFee` in Uniswap v2 with configurable fraction.
+    fn accrued_protocol_shares(&self) -> Balance {
+        if !self.protocol_fee_on() || self.k_last.is_zero() {
+            return 0;
         }
+        let root_k = (U256::from(self.near_amount) * U256::from(self.token_amount)).integer_sqrt();
+        let root_k_last = self.k_last.integer_sqrt();
+        if root_k <= root_k_last {
+            return 0;
+        }
+        let protocol_fee = U256::from(self.protocol_fee);
+        let numerator =
+            U256::from(self.shares_total_supply()) * (root_k - root_k_last) * protocol_fee;
+        let denominator =
+            root_k * (U256::from(PROTOCOL_FEE_DIVISOR) - protocol_fee) + root_k_last * protocol_fee;
+        (numerator / denominator).as_u128()
     }
 
+    /// Mints accrued protocol fee. Must be called before the number of shares changes.
+    fn mint_protocol_fee(&mut self) {
+        let liquidity = self.accrued_protocol_shares();
+        if liquidity > 0 {
+            let fee_to = self.fee_to.as_ref().expect("ERR_NO_FEE_TO");
+            self.shares.internal_deposit(fee_to, liquidity);
+        }
+    }
+
+    /// Records `k` to measure the fee growth from. Must be called after liquidity events.
+    fn update_k_last(&mut self) {
+        self.k_last = if self.protocol_fee_on() {
+            U256::from(self.near_amount) * U256::from(self.token_amount)
+        } else {
+            U256::zero()
+        };
+    }
+
+    /// Returns price accumulators as if they were updated at given timestamp.
+    /// Accumulators are expected to overflow, consumers should use wrapping difference
+    /// between two observations divided by elapsed time to get the average price.
+    fn cumulative_prices_at(&self, timestamp: u64) -> (U256, U256) {
+        let elapsed = U256::from(timestamp - self.block_timestamp_last);
+        if elapsed.is_zero() || self.near_amount == 0 || self.token_amount == 0 {
+            return (self.near_price_cumulative, self.token_price_cumulative);
+        }
+        let near_price = (U256::from(self.token_amount) << 128) / U256::from(self.near_amount);
+        let token_price = (U256::from(self.near_amount) << 128) / U256::from(self.token_amount);
+        (
+            self.near_price_cumulative
+                .overflowing_add(near_price.overflowing_mul(elapsed).0)
+                .0,
+            self.token_price_cumulative
+                .overflowing_add(token_price.overflowing_mul(elapsed).0)
+                .0,
+        )
+    }
+
+    /// Accumulates prices for the time since the last update. Must be called before reserves change.
+    fn update_price_accumulators(&mut self) {
+        let timestamp = env::block_timestamp();
+        let (near_price_cumulative, token_price_cumulative) = self.cumulative_prices_at(timestamp);
+        self.near_price_cumulative = near_price_cumulative;
+        self.token_price_cumulative = token_price_cumulative;
+        self.block_timestamp_last = timestamp;
+    }
+
+    /// Returns number of shares given account has. Same as `ft_balance_of`.
     pub fn shares_balance(&self, account_id: ValidAccountId) -> U128 {
-        self.shares
-            .get(account_id.as_ref())
-            .unwrap_or_default()
-            .into()
+        self.shares.ft_balance_of(account_id)
+    }
+
+    fn shares_total_supply(&self) -> Balance {
+        self.shares.ft_total_supply().0
     }
 }
 
-#[ext_contract(ext_fungible_token)]
-trait FungibleToken {
-    fn ft_transfer(&mut self, receiver_id: ValidAccountId, amount: U128, memo: Option<String>);
+#[near_bindgen]
+impl FungibleTokenCore for Contract {
+    #[payable]
+    fn ft_transfer(&mut self, receiver_id: ValidAccountId, amount: U128, memo: Option<String>) {
+        self.shares.ft_transfer(receiver_id, amount, memo)
+    }
+
+    #[payable]
+    fn ft_transfer_call(
+        &mut self,
+        receiver_id: ValidAccountId,
+        amount: U128,
+        msg: String,
+        memo: Option<String>,
+    ) -> Promise {
+        self.shares.ft_transfer_call(receiver_id, amount, msg, memo)
+    }
+
+    fn ft_total_supply(&self) -> U128 {
+        self.shares.ft_total_supply()
+    }
+
+    fn ft_balance_of(&self, account_id: ValidAccountId) -> U128 {
+        self.shares.ft_balance_of(account_id)
+    }
 }
 
-trait FungibleTokenReceiver {
-    /// Called by fungible token contract after `ft_transfer_call` was initiated by
-    /// `sender_id` of the given `amount` with the transfer message given in `msg` field.
-    /// The `amount` of tokens were already transferred to this contract account and ready to be used.
-    ///
-    /// The method must return the amount of tokens that are *not* used/accepted by this contract from the transferred
-    /// amount. Examples:
-    /// - The transferred amount was `500`, the contract completely takes it and must return `0`.
-    /// - The transferred amount was `500`, but this transfer call only needs `450` for the action passed in the `msg`
-    ///   field, then the method must return `50`.
-    /// - The transferred amount was `500`, but the action in `msg` field has expired and the transfer must be
-    ///   cancelled. The method must return `500` or panic.
-    ///
-    /// Arguments:
-    /// - `sender_id` - the account ID that initiated the transfer.
-    /// - `amount` - the amount of tokens that were transferred to this account in a decimal string representation.
-    /// - `msg` - a string message that was passed with this transfer call.
-    ///
-    /// Returns the amount of unused tokens that should be returned to sender, in a decimal string representation.
-    fn ft_on_transfer(&mut self, sender_id: ValidAccountId, amount: U128, msg: String) -> U128;
+#[near_bindgen]
+impl FungibleTokenResolver for Contract {
+    /// Refunds shares that the receiver of `ft_transfer_call` didn't use.
+    fn ft_resolve_transfer(
+        &mut self,
+        sender_id: ValidAccountId,
+        receiver_id: ValidAccountId,
+        amount: U128,
+    ) -> U128 {
+        assert_self();
+        self.shares
+            .ft_resolve_transfer(sender_id, receiver_id, amount)
+    }
 }
 
 #[near_bindgen]
-impl FungibleTokenReceiver for Contract {
-    fn ft_on_transfer(&mut self, sender_id: ValidAccountId, amount: U128, msg: String) -> U128 {
-        assert_eq!(
-            env::predecessor_account_id(),
-            self.token_account_id,
-            "ERR_WRONG_TOKEN"
-        );
-        if msg == "liquidity" {
-            self.finish_add_liquidity(sender_id.as_ref(), amount)
-        } else {
-            self.swap_token_to_near(
-                sender_id.as_ref(),
-                amount.into(),
-                serde_json::from_str::<U128>(&msg).expect("ERR_MSG").into(),
-            );
-            amount
+impl FungibleTokenMetadataProvider for Contract {
+    fn ft_metadata() -> FungibleTokenMetadata {
+        FungibleTokenMetadata {
+            spec: "ft-1.0.0".to_string(),
+            name: "Uniswap liquidity shares".to_string(),
+            symbol: "UNI-LP".to_string(),
+            icon: None,
+            reference: None,
+            reference_hash: None,
+            decimals: 24,
         }
     }
 }
 
+#[ext_contract(ext_fungible_token)]
+trait ExtFungibleToken {
+    fn ft_transfer(&mut self, receiver_id: ValidAccountId, amount: U128, memo: Option<String>);
+}
+
+#[ext_contract(ext_exchange)]
+trait Exchange {
+    fn swap_near_to_token(&mut self, min_amount: Balance, recipient: Option<ValidAccountId>);
+}
+
+#[ext_contract(ext_factory)]
+trait Factory {
+    fn get_token(&self, exchange_id: AccountId) -> Option<AccountId>;
+}
+
+#[ext_contract(ext_self)]
+trait ExtSelf {
+    fn on_get_exchange_token(
+        &mut self,
+        recipient: AccountId,
+        token_amount: U128,
+        exchange_id: AccountId,
+        min_amount_out: U128,
+    ) -> PromiseOrValue<U128>;
+    fn on_swap_near_to_token(
+        &mut self,
+        account_id: AccountId,
+        near_amount: U128,
+        token_amount: U128,
+    ) -> U128;
+    fn on_swap_token_to_token(&mut self, token_amount: U128, near_amount: U128) -> U128;
+    fn on_remove_liquidity(
+        &mut self,
+        account_id: AccountId,
+        shares: U128,
+        near_amount: U128,
+        token_amount: U128,
+    );
+}
+
 pub fn add_to_collection(
     c: &mut LookupMap<AccountId, Balance>,
     account_id: &AccountId,
@@ -266,12 +762,32 @@ pub fn add_to_collection(
     c.insert(account_id, &(prev_amount + amount));
 }
 
+/// Asserts that the method was called by the contract itself.
+fn assert_self() {
+    assert_eq!(
+        env::predecessor_account_id(),
+        env::current_account_id(),
+        "ERR_NOT_ALLOWED"
+    );
+}
+
+/// Returns true if the promise this callback is attached to succeeded.
+fn is_promise_success() -> bool {
+    assert_eq!(env::promise_results_count(), 1, "ERR_PROMISE_RESULTS");
+    match env::promise_result(0) {
+        PromiseResult::Successful(_) => true,
+        _ => false,
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_contract_standards::storage_manager::StorageManager;
+    use near_sdk::test_utils::{accounts, testing_env_with_promise_results, VMContextBuilder};
     use near_sdk::{testing_env, MockedBlockchain};
 
     use super::*;
+    use crate::token_receiver::FungibleTokenReceiver;
 
     #[test]
     fn test_basics() {
@@ -279,14 +795,18 @@ mod tests {
         let mut context = VMContextBuilder::new();
         context.predecessor_account_id(accounts(0));
         testing_env!(context.build());
-        let mut contract = Contract::new(accounts(1), 3);
+        let mut contract = Contract::new(accounts(1), 3, None);
+        testing_env!(context
+            .attached_deposit(contract.storage_minimum_balance().into())
+            .build());
+        contract.storage_deposit(None);
         testing_env!(context.attached_deposit(5 * one_near).build());
         contract.add_liquidity();
         testing_env!(context.predecessor_account_id(accounts(1)).build());
         contract.ft_on_transfer(
             accounts(0).into(),
             (10 * one_near).into(),
-            "liquidity".to_string(),
+            r#"{"v1": {"action": "add_liquidity", "min_shares": "0"}}"#.to_string(),
         );
 
         let price = contract.get_near_to_token_price(one_near);
@@ -296,10 +816,11 @@ mod tests {
 
         // Swap 1N for tokens, check that pool has 1N more and result tokens less.
         testing_env!(context.attached_deposit(one_near).build());
-        let result = contract.swap_near_to_token(1);
+        let expected = contract.get_input_price(one_near, 5 * one_near, 10 * one_near);
+        contract.swap_near_to_token(1, None);
 
         assert_eq!(contract.near_amount, 6 * one_near);
-        assert_eq!(contract.token_amount, 10 * one_near - result);
+        assert_eq!(contract.token_amount, 10 * one_near - expected);
 
         // Withdraw all liquidity, check that nothing left.
         testing_env!(context.predecessor_account_id(accounts(0)).build());
@@ -307,4 +828,417 @@ mod tests {
         assert_eq!(contract.near_amount, 0);
         assert_eq!(contract.token_amount, 0);
     }
+
+    #[test]
+    fn test_swap_token_to_near() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1), 3, None);
+        testing_env!(context
+            .attached_deposit(contract.storage_minimum_balance().into())
+            .build());
+        contract.storage_deposit(None);
+        testing_env!(context.attached_deposit(5 * one_near).build());
+        contract.add_liquidity();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.ft_on_transfer(
+            accounts(0).into(),
+            (10 * one_near).into(),
+            r#"{"v1": {"action": "add_liquidity", "min_shares": "0"}}"#.to_string(),
+        );
+
+        // Sell 1 token: the pool keeps it in the reserve and pays out NEAR, nothing is refunded.
+        let expected = contract.get_token_to_near_price(one_near);
+        match contract.ft_on_transfer(
+            accounts(0).into(),
+            one_near.into(),
+            r#"{"v1": {"action": "swap", "min_near_out": "1"}}"#.to_string(),
+        ) {
+            near_sdk::PromiseOrValue::Value(refund) => assert_eq!(refund, U128(0)),
+            _ => panic!("Swap to NEAR must not refund via promise"),
+        }
+        assert_eq!(contract.token_amount, 11 * one_near);
+        assert_eq!(contract.near_amount, 5 * one_near - expected);
+    }
+
+    #[test]
+    fn test_add_liquidity_refund() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1), 3, None);
+        testing_env!(context
+            .attached_deposit(contract.storage_minimum_balance().into())
+            .build());
+        contract.storage_deposit(None);
+        testing_env!(context.attached_deposit(5 * one_near).build());
+        contract.add_liquidity();
+        assert_eq!(
+            contract.finish_add_liquidity(&accounts(0).into(), 10 * one_near, 0),
+            0
+        );
+
+        // Adding 1N of liquidity requires 2 tokens, the rest is refunded.
+        testing_env!(context.attached_deposit(one_near).build());
+        contract.add_liquidity();
+        let refund = contract.finish_add_liquidity(&accounts(0).into(), 3 * one_near, one_near);
+        assert_eq!(refund, one_near - 1);
+        assert_eq!(contract.shares_balance(accounts(0)), (6 * one_near).into());
+        assert_eq!(contract.token_amount, 12 * one_near + 1);
+
+        // Deposited NEAR that was never paired can be withdrawn.
+        testing_env!(context.attached_deposit(one_near).build());
+        contract.add_liquidity();
+        assert_eq!(contract.get_near_balance(accounts(0)), one_near.into());
+        contract.withdraw_near();
+        assert_eq!(contract.get_near_balance(accounts(0)), 0.into());
+    }
+
+    #[test]
+    fn test_swap_exact_out() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1), 3, None);
+        testing_env!(context
+            .attached_deposit(contract.storage_minimum_balance().into())
+            .build());
+        contract.storage_deposit(None);
+        testing_env!(context.attached_deposit(5 * one_near).build());
+        contract.add_liquidity();
+        contract.finish_add_liquidity(&accounts(0).into(), 10 * one_near, 0);
+
+        // Buy exactly 1 token, the price is taken from the attached NEAR.
+        let near_sold = contract.get_near_to_token_price(one_near) + 1;
+        testing_env!(context.attached_deposit(2 * one_near).build());
+        contract.swap_near_to_token_exact_out(one_near.into(), None);
+        assert_eq!(contract.near_amount, 5 * one_near + near_sold);
+        assert_eq!(contract.token_amount, 9 * one_near);
+
+        // Sell tokens for exactly 1N, unused tokens are returned.
+        let tokens_sold = contract.get_token_to_near_price(one_near) + 1;
+        let refund =
+            contract.swap_token_to_near_exact_out(&accounts(0).into(), 5 * one_near, one_near);
+        assert_eq!(refund, 5 * one_near - tokens_sold);
+        assert_eq!(contract.near_amount, 4 * one_near + near_sold);
+        assert_eq!(contract.token_amount, 9 * one_near + tokens_sold);
+    }
+
+    #[test]
+    fn test_price_accumulators() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1), 3, None);
+        testing_env!(context
+            .attached_deposit(contract.storage_minimum_balance().into())
+            .build());
+        contract.storage_deposit(None);
+        testing_env!(context.attached_deposit(5 * one_near).build());
+        contract.add_liquidity();
+        contract.finish_add_liquidity(&accounts(0).into(), 10 * one_near, 0);
+
+        // 10 seconds later price of NEAR is 2 tokens and price of token is 0.5 NEAR.
+        let elapsed = 10u64.pow(10);
+        testing_env!(context.block_timestamp(elapsed).build());
+        let prices = contract.get_cumulative_prices();
+        assert_eq!(
+            prices.near_price_cumulative,
+            ((U256::from(2) << 128) * U256::from(elapsed)).to_string()
+        );
+        assert_eq!(
+            prices.token_price_cumulative,
+            ((U256::from(1) << 127) * U256::from(elapsed)).to_string()
+        );
+        assert_eq!(prices.block_timestamp, elapsed.into());
+
+        // Swap updates accumulators with the reserves before the swap.
+        testing_env!(context.attached_deposit(one_near).build());
+        contract.swap_near_to_token(1, None);
+        let reserves = contract.get_reserves();
+        assert_eq!(reserves.near_amount, (6 * one_near).into());
+        assert_eq!(reserves.block_timestamp_last, elapsed.into());
+        assert_eq!(
+            contract.get_cumulative_prices().near_price_cumulative,
+            prices.near_price_cumulative
+        );
+    }
+
+    #[test]
+    fn test_protocol_fee() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1), 3, None);
+        testing_env!(context
+            .attached_deposit(contract.storage_minimum_balance().into())
+            .build());
+        contract.storage_deposit(None);
+        contract.storage_deposit(Some(accounts(2)));
+        contract.set_protocol_fee(Some(accounts(2)), 500);
+        testing_env!(context.attached_deposit(5 * one_near).build());
+        contract.add_liquidity();
+        contract.finish_add_liquidity(&accounts(0).into(), 10 * one_near, 0);
+        assert_eq!(contract.get_accrued_protocol_shares(), 0.into());
+
+        // Fees from swaps grow `k`, half of it is accrued to `fee_to`.
+        testing_env!(context.attached_deposit(one_near).build());
+        contract.swap_near_to_token(1, None);
+        let accrued = contract.get_accrued_protocol_shares();
+        assert!(accrued.0 > 0);
+
+        // Next liquidity event mints accrued shares.
+        testing_env!(context.attached_deposit(one_near).build());
+        contract.add_liquidity();
+        contract.finish_add_liquidity(&accounts(0).into(), 2 * one_near, 0);
+        assert_eq!(contract.shares_balance(accounts(2)), accrued);
+        assert_eq!(contract.get_accrued_protocol_shares(), 0.into());
+
+        // Turning protocol fee off stops accruing.
+        testing_env!(context.attached_deposit(0).build());
+        contract.set_protocol_fee(None, 0);
+        testing_env!(context.attached_deposit(one_near).build());
+        contract.swap_near_to_token(1, None);
+        assert_eq!(contract.get_accrued_protocol_shares(), 0.into());
+    }
+
+    #[test]
+    fn test_storage() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1), 3, None);
+        let min_balance = contract.storage_minimum_balance();
+        testing_env!(context.attached_deposit(min_balance.0 + one_near).build());
+        let balance = contract.storage_deposit(None);
+        assert_eq!(balance.total, min_balance);
+        assert_eq!(balance.available, min_balance);
+
+        // Pending NEAR and shares lock the storage.
+        testing_env!(context.attached_deposit(5 * one_near).build());
+        contract.add_liquidity();
+        assert_eq!(contract.storage_balance_of(accounts(0)).available, 0.into());
+        contract.finish_add_liquidity(&accounts(0).into(), 10 * one_near, 0);
+        assert_eq!(contract.storage_balance_of(accounts(0)).available, 0.into());
+
+        // Removal in flight locks the storage until the token transfer is resolved.
+        let shares = contract.shares_balance(accounts(0));
+        contract.remove_liquidity(shares, 1.into(), 1.into());
+        assert_eq!(contract.storage_balance_of(accounts(0)).available, 0.into());
+        testing_env_with_promise_results(context.build(), PromiseResult::Successful(vec![]));
+        contract.on_remove_liquidity(
+            accounts(0).into(),
+            shares,
+            (5 * one_near).into(),
+            (10 * one_near).into(),
+        );
+
+        // After exit the storage can be withdrawn, which unregisters the account.
+        assert_eq!(
+            contract.storage_balance_of(accounts(0)).available,
+            min_balance
+        );
+        testing_env!(context.attached_deposit(1).build());
+        let balance = contract.storage_withdraw(min_balance);
+        assert_eq!(balance.total, 0.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_STORAGE_DEPOSIT")]
+    fn test_add_liquidity_not_registered() {
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1), 3, None);
+        testing_env!(context.attached_deposit(10u128.pow(24)).build());
+        contract.add_liquidity();
+    }
+
+    #[test]
+    fn test_remove_liquidity_transfer_failed() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        context
+            .current_account_id(accounts(2))
+            .predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1), 3, None);
+        testing_env!(context
+            .attached_deposit(contract.storage_minimum_balance().into())
+            .build());
+        contract.storage_deposit(None);
+        testing_env!(context.attached_deposit(5 * one_near).build());
+        contract.add_liquidity();
+        contract.finish_add_liquidity(&accounts(0).into(), 10 * one_near, 0);
+        let shares = contract.shares_balance(accounts(0));
+
+        // All shares are removed, but the account can't unregister while the transfer is in flight.
+        testing_env!(context.attached_deposit(0).build());
+        contract.remove_liquidity(shares, 1.into(), 1.into());
+        assert_eq!(contract.storage_balance_of(accounts(0)).available, 0.into());
+
+        // Token transfer failed: shares and reserves are restored.
+        testing_env_with_promise_results(
+            context.predecessor_account_id(accounts(2)).build(),
+            PromiseResult::Failed,
+        );
+        contract.on_remove_liquidity(
+            accounts(0).into(),
+            shares,
+            (5 * one_near).into(),
+            (10 * one_near).into(),
+        );
+        assert_eq!(contract.shares_balance(accounts(0)), shares);
+        assert_eq!(contract.near_amount, 5 * one_near);
+        assert_eq!(contract.token_amount, 10 * one_near);
+        assert_eq!(contract.storage_balance_of(accounts(0)).available, 0.into());
+    }
+
+    #[test]
+    fn test_remove_liquidity_transfer_failed_protocol_fee() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1), 3, None);
+        testing_env!(context
+            .attached_deposit(contract.storage_minimum_balance().into())
+            .build());
+        contract.storage_deposit(None);
+        contract.storage_deposit(Some(accounts(2)));
+        contract.set_protocol_fee(Some(accounts(2)), 500);
+        testing_env!(context.attached_deposit(5 * one_near).build());
+        contract.add_liquidity();
+        contract.finish_add_liquidity(&accounts(0).into(), 10 * one_near, 0);
+        let shares = contract.shares_balance(accounts(0));
+
+        testing_env!(context.attached_deposit(0).build());
+        contract.remove_liquidity((shares.0 / 2).into(), 1.into(), 1.into());
+        let (near_amount, token_amount) = (contract.near_amount, contract.token_amount);
+
+        // Swap while the removal is in flight grows `k`.
+        testing_env!(context.attached_deposit(one_near).build());
+        contract.swap_near_to_token(1, None);
+        let accrued = contract.get_accrued_protocol_shares();
+        assert!(accrued.0 > 0);
+
+        // Restoring the reserves mints the fee accrued so far instead of dropping it.
+        testing_env_with_promise_results(
+            context.attached_deposit(0).build(),
+            PromiseResult::Failed,
+        );
+        contract.on_remove_liquidity(
+            accounts(0).into(),
+            (shares.0 / 2).into(),
+            (5 * one_near - near_amount).into(),
+            (10 * one_near - token_amount).into(),
+        );
+        assert_eq!(contract.shares_balance(accounts(0)), shares);
+        assert_eq!(contract.shares_balance(accounts(2)), accrued);
+        assert_eq!(contract.get_accrued_protocol_shares(), 0.into());
+    }
+
+    #[test]
+    fn test_shares_transfer_call_refund() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        context
+            .current_account_id(accounts(2))
+            .predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1), 3, None);
+        let min_balance = contract.storage_minimum_balance();
+        testing_env!(context.attached_deposit(min_balance.0).build());
+        contract.storage_deposit(None);
+        contract.storage_deposit(Some(accounts(3)));
+        testing_env!(context.attached_deposit(5 * one_near).build());
+        contract.add_liquidity();
+        contract.finish_add_liquidity(&accounts(0).into(), 10 * one_near, 0);
+        let shares = contract.ft_balance_of(accounts(0)).0;
+
+        testing_env!(context.attached_deposit(1).build());
+        contract.ft_transfer_call(accounts(3), one_near.into(), "".to_string(), None);
+        assert_eq!(contract.ft_balance_of(accounts(3)), U128(one_near));
+
+        // Receiver used only a quarter of the shares, the rest goes back to the sender.
+        testing_env_with_promise_results(
+            context
+                .predecessor_account_id(accounts(2))
+                .attached_deposit(0)
+                .build(),
+            PromiseResult::Successful(format!("\"{}\"", 3 * one_near / 4).into_bytes()),
+        );
+        let used = contract.ft_resolve_transfer(accounts(0), accounts(3), one_near.into());
+        assert_eq!(used, U128(one_near / 4));
+        assert_eq!(contract.ft_balance_of(accounts(3)), U128(one_near / 4));
+        assert_eq!(
+            contract.ft_balance_of(accounts(0)),
+            U128(shares - one_near / 4)
+        );
+    }
+
+    #[test]
+    fn test_swap_to_token_checks_factory_registry() {
+        let one_near = 10u128.pow(24);
+        let mut context = VMContextBuilder::new();
+        context
+            .current_account_id(accounts(2))
+            .predecessor_account_id(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1), 3, None);
+        testing_env!(context
+            .attached_deposit(contract.storage_minimum_balance().into())
+            .build());
+        contract.storage_deposit(None);
+        testing_env!(context.attached_deposit(5 * one_near).build());
+        contract.add_liquidity();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.ft_on_transfer(
+            accounts(0).into(),
+            (10 * one_near).into(),
+            r#"{"v1": {"action": "add_liquidity", "min_shares": "0"}}"#.to_string(),
+        );
+
+        // Sub-account that the factory doesn't know: tokens are refunded, reserves unchanged.
+        let context = context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(0)
+            .build();
+        testing_env_with_promise_results(
+            context.clone(),
+            PromiseResult::Successful(b"null".to_vec()),
+        );
+        let exchange_id = format!("fake.{}", accounts(0));
+        match contract.on_get_exchange_token(
+            accounts(3).into(),
+            one_near.into(),
+            exchange_id.clone(),
+            1.into(),
+        ) {
+            PromiseOrValue::Value(refund) => assert_eq!(refund, U128(one_near)),
+            _ => panic!("Tokens must be refunded"),
+        }
+        assert_eq!(contract.token_amount, 10 * one_near);
+        assert_eq!(contract.near_amount, 5 * one_near);
+
+        // Registered exchange: tokens are sold to NEAR and the swap continues there.
+        testing_env_with_promise_results(context, PromiseResult::Successful(b"\"token\"".to_vec()));
+        match contract.on_get_exchange_token(
+            accounts(3).into(),
+            one_near.into(),
+            exchange_id,
+            1.into(),
+        ) {
+            PromiseOrValue::Promise(_) => {}
+            _ => panic!("Swap must continue on the other exchange"),
+        }
+        assert_eq!(contract.token_amount, 11 * one_near);
+    }
 }
@@ -0,0 +1,120 @@
+//! NEP-145 storage management: each account pays for it's shares and pending NEAR entries.
+
+use near_contract_standards::storage_manager::{AccountStorageBalance, StorageManager};
+use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::{env, near_bindgen, AccountId, Promise};
+
+use crate::*;
+
+impl Contract {
+    /// Measures storage of the longest account in shares and pending NEAR balances.
+    pub(crate) fn measure_account_storage_usage(&mut self) {
+        let initial_storage_usage = env::storage_usage();
+        let tmp_account_id = "a".repeat(64);
+        self.near_balances.insert(&tmp_account_id, &0);
+        self.pending_removals.insert(&tmp_account_id, &0);
+        self.account_storage_usage =
+            env::storage_usage() - initial_storage_usage + self.shares.account_storage_usage;
+        self.near_balances.remove(&tmp_account_id);
+        self.pending_removals.remove(&tmp_account_id);
+    }
+
+    /// Counts `remove_liquidity` in flight for the account, so it can't unregister before
+    /// the callback restores it's shares if the token transfer fails.
+    pub(crate) fn internal_update_pending_removals(&mut self, account_id: &AccountId, add: bool) {
+        let count = self.pending_removals.get(account_id).unwrap_or(0);
+        let count = if add {
+            count + 1
+        } else {
+            count.saturating_sub(1)
+        };
+        if count > 0 {
+            self.pending_removals.insert(account_id, &count);
+        } else {
+            self.pending_removals.remove(account_id);
+        }
+    }
+
+    pub(crate) fn assert_registered(&self, account_id: &AccountId) {
+        assert!(
+            self.shares.accounts.contains_key(account_id),
+            "ERR_STORAGE_DEPOSIT"
+        );
+    }
+
+    /// Storage can be released when account has no shares, no pending NEAR, no liquidity
+    /// removal in flight and doesn't receive the protocol fee.
+    fn is_storage_in_use(&self, account_id: &AccountId) -> bool {
+        self.shares.accounts.get(account_id).unwrap_or(0) > 0
+            || self.near_balances.contains_key(account_id)
+            || self.pending_removals.contains_key(account_id)
+            || self.fee_to.as_ref() == Some(account_id)
+    }
+}
+
+#[near_bindgen]
+impl StorageManager for Contract {
+    /// Registers given account (or the caller) paying for it's storage.
+    /// Deposit above the minimum balance is refunded, same as the whole deposit if already registered.
+    #[payable]
+    fn storage_deposit(&mut self, account_id: Option<ValidAccountId>) -> AccountStorageBalance {
+        let amount = env::attached_deposit();
+        let account_id = account_id
+            .map(|a| a.into())
+            .unwrap_or_else(env::predecessor_account_id);
+        if self.shares.accounts.contains_key(&account_id) {
+            env::log(b"The account is already registered, refunding the deposit");
+            if amount > 0 {
+                Promise::new(env::predecessor_account_id()).transfer(amount);
+            }
+        } else {
+            let min_balance = self.storage_minimum_balance().0;
+            assert!(amount >= min_balance, "ERR_STORAGE_DEPOSIT_TOO_LOW");
+            self.shares.internal_register_account(&account_id);
+            let refund = amount - min_balance;
+            if refund > 0 {
+                Promise::new(env::predecessor_account_id()).transfer(refund);
+            }
+        }
+        self.storage_balance_of(account_id.try_into().unwrap())
+    }
+
+    /// Unregisters the caller if `amount` is the whole storage balance and it's not in use,
+    /// i.e. the account has no shares, no pending NEAR and no liquidity removal in flight.
+    /// Zero `amount` returns the balance.
+    #[payable]
+    fn storage_withdraw(&mut self, amount: U128) -> AccountStorageBalance {
+        assert_eq!(env::attached_deposit(), 1, "ERR_REQUIRES_ONE_YOCTO");
+        let account_id = env::predecessor_account_id();
+        self.assert_registered(&account_id);
+        if amount.0 > 0 {
+            let storage_balance = self.storage_minimum_balance().0;
+            assert_eq!(amount.0, storage_balance, "ERR_WRONG_AMOUNT");
+            assert!(!self.is_storage_in_use(&account_id), "ERR_STORAGE_IN_USE");
+            self.shares.accounts.remove(&account_id);
+            Promise::new(account_id.clone()).transfer(storage_balance + 1);
+        }
+        self.storage_balance_of(account_id.try_into().unwrap())
+    }
+
+    fn storage_minimum_balance(&self) -> U128 {
+        (Balance::from(self.account_storage_usage) * env::storage_byte_cost()).into()
+    }
+
+    /// Storage balance is available for withdrawal once the account doesn't use it.
+    fn storage_balance_of(&self, account_id: ValidAccountId) -> AccountStorageBalance {
+        if !self.shares.accounts.contains_key(account_id.as_ref()) {
+            return AccountStorageBalance {
+                total: 0.into(),
+                available: 0.into(),
+            };
+        }
+        let total = self.storage_minimum_balance();
+        let available = if self.is_storage_in_use(account_id.as_ref()) {
+            0.into()
+        } else {
+            total
+        };
+        AccountStorageBalance { total, available }
+    }
+}
@@ -0,0 +1,265 @@
+//! Receiving tokens via `ft_transfer_call` and the message protocol for it.
+
+use near_sdk::json_types::U64;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json::Value;
+use near_sdk::PromiseOrValue;
+
+use crate::*;
+
+/// Message is not a valid JSON or misses required fields.
+const ERR_MSG_MALFORMED: &str = "ERR_MSG_MALFORMED";
+/// Message has unknown version or action, tokens are refunded.
+const ERR_MSG_UNKNOWN: &str = "ERR_MSG_UNKNOWN";
+/// Message deadline has passed.
+const ERR_DEADLINE: &str = "ERR_DEADLINE";
+
+/// Versions of `VersionedMessage`.
+const KNOWN_VERSIONS: &[&str] = &["v1"];
+/// Action tags of `Message`.
+const KNOWN_ACTIONS: &[&str] = &["add_liquidity", "swap", "swap_exact_out", "swap_to_token"];
+/// Deprecated message to add liquidity without `min_shares`.
+const LEGACY_LIQUIDITY_MSG: &str = "liquidity";
+
+/// Message passed to `ft_transfer_call`, wrapped into it's version.
+/// For example: `{"v1": {"action": "swap", "min_near_out": "100"}}`.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum VersionedMessage {
+    V1(Message),
+}
+
+/// Action to perform with the received tokens.
+/// `deadline` is block timestamp in nanoseconds after which the action is rejected.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Message {
+    /// Pairs tokens with NEAR deposited via `add_liquidity`, unused tokens are refunded.
+    AddLiquidity { min_shares: U128 },
+    /// Swaps all tokens to NEAR, sent to `recipient` or to the sender.
+    Swap {
+        min_near_out: U128,
+        recipient: Option<ValidAccountId>,
+        deadline: Option<U64>,
+    },
+    /// Swaps tokens to exactly `near_out` NEAR, unused tokens are refunded.
+    SwapExactOut {
+        near_out: U128,
+        recipient: Option<ValidAccountId>,
+        deadline: Option<U64>,
+    },
+    /// Swaps all tokens to the token of `exchange_id` exchange created by the same factory.
+    SwapToToken {
+        exchange_id: ValidAccountId,
+        min_amount_out: U128,
+        recipient: Option<ValidAccountId>,
+        deadline: Option<U64>,
+    },
+}
+
+/// Parses message, returning error code if it's malformed or unknown.
+/// Deprecated messages are still accepted: `liquidity` adds liquidity without `min_shares`
+/// and a bare amount like `"100"` swaps all tokens to at least that much NEAR.
+fn parse_message(msg: &str) -> Result<Message, &'static str> {
+    if msg == LEGACY_LIQUIDITY_MSG {
+        return Ok(Message::AddLiquidity {
+            min_shares: U128(0),
+        });
+    }
+    let value: Value = serde_json::from_str(msg).map_err(|_| ERR_MSG_MALFORMED)?;
+    if value.is_string() {
+        return serde_json::from_value::<U128>(value)
+            .map(|min_near_out| Message::Swap {
+                min_near_out,
+                recipient: None,
+                deadline: None,
+            })
+            .map_err(|_| ERR_MSG_MALFORMED);
+    }
+    match value.as_object() {
+        Some(versions) if versions.len() == 1 => {
+            let (version, message) = versions.iter().next().unwrap();
+            if !KNOWN_VERSIONS.contains(&version.as_str()) {
+                return Err(ERR_MSG_UNKNOWN);
+            }
+            match message.get("action").and_then(Value::as_str) {
+                Some(action) if !KNOWN_ACTIONS.contains(&action) => return Err(ERR_MSG_UNKNOWN),
+                Some(_) => {}
+                None => return Err(ERR_MSG_MALFORMED),
+            }
+        }
+        _ => return Err(ERR_MSG_MALFORMED),
+    }
+    match serde_json::from_value::<VersionedMessage>(value) {
+        Ok(VersionedMessage::V1(message)) => Ok(message),
+        Err(_) => Err(ERR_MSG_MALFORMED),
+    }
+}
+
+fn assert_deadline(deadline: Option<U64>) {
+    if let Some(deadline) = deadline {
+        assert!(env::block_timestamp() <= deadline.0, "{}", ERR_DEADLINE);
+    }
+}
+
+pub trait FungibleTokenReceiver {
+    /// Called by fungible token contract after `ft_transfer_call` was initiated by
+    /// `sender_id` of the given `amount` with the transfer message given in `msg` field.
+    /// The `amount` of tokens were already transferred to this contract account and ready to be used.
+    ///
+    /// The method must return the amount of tokens that are *not* used/accepted by this contract from the transferred
+    /// amount. Examples:
+    /// - The transferred amount was `500`, the contract completely takes it and must return `0`.
+    /// - The transferred amount was `500`, but this transfer call only needs `450` for the action passed in the `msg`
+    ///   field, then the method must return `50`.
+    /// - The transferred amount was `500`, but the action in `msg` field has expired and the transfer must be
+    ///   cancelled. The method must return `500` or panic.
+    ///
+    /// Arguments:
+    /// - `sender_id` - the account ID that initiated the transfer.
+    /// - `amount` - the amount of tokens that were transferred to this account in a decimal string representation.
+    /// - `msg` - a string message that was passed with this transfer call.
+    ///
+    /// Returns the amount of unused tokens that should be returned to sender, in a decimal string representation.
+    fn ft_on_transfer(
+        &mut self,
+        sender_id: ValidAccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128>;
+}
+
+#[near_bindgen]
+impl FungibleTokenReceiver for Contract {
+    /// Callback on receiving tokens by this contract. `msg` must be a `VersionedMessage`.
+    fn ft_on_transfer(
+        &mut self,
+        sender_id: ValidAccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.token_account_id,
+            "ERR_WRONG_TOKEN"
+        );
+        let message = match parse_message(&msg) {
+            Ok(message) => message,
+            Err(ERR_MSG_UNKNOWN) => {
+                env::log(format!("{}: refunding {}", ERR_MSG_UNKNOWN, amount.0).as_bytes());
+                return PromiseOrValue::Value(amount);
+            }
+            Err(err) => env::panic(err.as_bytes()),
+        };
+        let sender_id: AccountId = sender_id.into();
+        match message {
+            Message::AddLiquidity { min_shares } => PromiseOrValue::Value(
+                self.finish_add_liquidity(&sender_id, amount.into(), min_shares.into())
+                    .into(),
+            ),
+            Message::Swap {
+                min_near_out,
+                recipient,
+                deadline,
+            } => {
+                assert_deadline(deadline);
+                self.swap_token_to_near(
+                    &recipient.map(|a| a.into()).unwrap_or(sender_id),
+                    amount.into(),
+                    min_near_out.into(),
+                );
+                PromiseOrValue::Value(U128(0))
+            }
+            Message::SwapExactOut {
+                near_out,
+                recipient,
+                deadline,
+            } => {
+                assert_deadline(deadline);
+                PromiseOrValue::Value(
+                    self.swap_token_to_near_exact_out(
+                        &recipient.map(|a| a.into()).unwrap_or(sender_id),
+                        amount.into(),
+                        near_out.into(),
+                    )
+                    .into(),
+                )
+            }
+            Message::SwapToToken {
+                exchange_id,
+                min_amount_out,
+                recipient,
+                deadline,
+            } => {
+                assert_deadline(deadline);
+                PromiseOrValue::Promise(self.swap_token_to_token(
+                    &recipient.map(|a| a.into()).unwrap_or(sender_id),
+                    amount.into(),
+                    exchange_id.as_ref(),
+                    min_amount_out.into(),
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_message() {
+        match parse_message(r#"{"v1": {"action": "add_liquidity", "min_shares": "10"}}"#) {
+            Ok(Message::AddLiquidity { min_shares }) => assert_eq!(min_shares, U128(10)),
+            _ => panic!("Wrong message"),
+        }
+        match parse_message(r#"{"v1": {"action": "swap", "min_near_out": "5"}}"#) {
+            Ok(Message::Swap {
+                min_near_out,
+                recipient,
+                deadline,
+            }) => {
+                assert_eq!(min_near_out, U128(5));
+                assert!(recipient.is_none() && deadline.is_none());
+            }
+            _ => panic!("Wrong message"),
+        }
+        assert_eq!(
+            parse_message(r#"{"v2": {"action": "swap", "min_near_out": "5"}}"#).err(),
+            Some(ERR_MSG_UNKNOWN)
+        );
+        assert_eq!(
+            parse_message(r#"{"v1": {"action": "borrow"}}"#).err(),
+            Some(ERR_MSG_UNKNOWN)
+        );
+        assert_eq!(
+            parse_message(r#"{"v1": {"action": "swap"}}"#).err(),
+            Some(ERR_MSG_MALFORMED)
+        );
+        assert_eq!(
+            parse_message(r#"{"v1": {"min_near_out": "5"}}"#).err(),
+            Some(ERR_MSG_MALFORMED)
+        );
+        assert_eq!(parse_message("[1]").err(), Some(ERR_MSG_MALFORMED));
+        assert_eq!(parse_message("liquid").err(), Some(ERR_MSG_MALFORMED));
+
+        // Deprecated messages.
+        match parse_message("liquidity") {
+            Ok(Message::AddLiquidity { min_shares }) => assert_eq!(min_shares, U128(0)),
+            _ => panic!("Wrong message"),
+        }
+        match parse_message(r#""7""#) {
+            Ok(Message::Swap {
+                min_near_out,
+                recipient,
+                deadline,
+            }) => {
+                assert_eq!(min_near_out, U128(7));
+                assert!(recipient.is_none() && deadline.is_none());
+            }
+            _ => panic!("Wrong message"),
+        }
+    }
+}
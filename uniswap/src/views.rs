@@ -0,0 +1,85 @@
+//! View functions for price oracles and protocol fee.
+
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{near_bindgen, AccountId};
+
+use crate::*;
+
+/// Reserves of the exchange and the time they were last updated.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Reserves {
+    /// How much NEAR this contract has.
+    pub near_amount: U128,
+    /// How much token this contract has.
+    pub token_amount: U128,
+    /// Block timestamp in nanoseconds of the last reserves update.
+    pub block_timestamp_last: U64,
+}
+
+/// Cumulative prices as Q128 fixed point numbers multiplied by nanoseconds.
+/// Time weighted average price between two observations is
+/// `(cumulative_2 - cumulative_1) / (block_timestamp_2 - block_timestamp_1) / 2^128`,
+/// where the difference is taken modulo 2^256.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CumulativePrices {
+    /// Cumulative price of NEAR in tokens, in decimal representation.
+    pub near_price_cumulative: String,
+    /// Cumulative price of token in NEAR, in decimal representation.
+    pub token_price_cumulative: String,
+    /// Block timestamp in nanoseconds of the observation.
+    pub block_timestamp: U64,
+}
+
+/// Protocol fee configuration.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ProtocolFee {
+    /// Account that can configure the protocol fee.
+    pub owner_id: AccountId,
+    /// Account receiving the protocol fee as shares.
+    pub fee_to: Option<AccountId>,
+    /// Fraction of the fee growth that goes to `fee_to`, out of 1000.
+    pub protocol_fee: u32,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Returns current reserves and the timestamp of their last update.
+    pub fn get_reserves(&self) -> Reserves {
+        Reserves {
+            near_amount: self.near_amount.into(),
+            token_amount: self.token_amount.into(),
+            block_timestamp_last: self.block_timestamp_last.into(),
+        }
+    }
+
+    /// Returns cumulative prices as of the current block, including time since the last update.
+    pub fn get_cumulative_prices(&self) -> CumulativePrices {
+        let block_timestamp = env::block_timestamp();
+        let (near_price_cumulative, token_price_cumulative) =
+            self.cumulative_prices_at(block_timestamp);
+        CumulativePrices {
+            near_price_cumulative: near_price_cumulative.to_string(),
+            token_price_cumulative: token_price_cumulative.to_string(),
+            block_timestamp: block_timestamp.into(),
+        }
+    }
+
+    /// Returns protocol fee configuration.
+    pub fn get_protocol_fee(&self) -> ProtocolFee {
+        ProtocolFee {
+            owner_id: self.owner_id.clone(),
+            fee_to: self.fee_to.clone(),
+            protocol_fee: self.protocol_fee,
+        }
+    }
+
+    /// Returns protocol fee shares accrued since the last liquidity event, that are not minted yet.
+    /// Already minted shares are returned by `ft_balance_of` of `fee_to`.
+    pub fn get_accrued_protocol_shares(&self) -> U128 {
+        self.accrued_protocol_shares().into()
+    }
+}
@@ -0,0 +1,279 @@
+use std::convert::TryFrom;
+use std::process::Command;
+use std::sync::Once;
+
+use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::serde_json::{json, Value};
+use near_sdk::AccountId;
+use near_sdk_sim::{
+    call, deploy, init_simulator, to_yocto, view, ContractAccount, ExecutionResult, UserAccount,
+    DEFAULT_GAS,
+};
+
+use uniswap::{ContractContract as Exchange, Reserves};
+
+near_sdk_sim::lazy_static_include::lazy_static_include_bytes! {
+    TEST_TOKEN_WASM_BYTES => "../test-token/res/test_token.wasm",
+}
+
+/// Builds the exchange with `build.sh` once per test run, so the tests never run a stale
+/// `res/uniswap.wasm`. Requires the `wasm32-unknown-unknown` target.
+fn exchange_wasm_bytes() -> Vec<u8> {
+    static BUILD: Once = Once::new();
+    BUILD.call_once(|| {
+        let status = Command::new("./build.sh")
+            .current_dir(env!("CARGO_MANIFEST_DIR"))
+            .status()
+            .expect("Failed to run build.sh");
+        assert!(status.success(), "Failed to build the exchange");
+    });
+    std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/res/uniswap.wasm")).unwrap()
+}
+
+fn dai() -> AccountId {
+    "dai".to_string()
+}
+
+fn exchange() -> AccountId {
+    "exchange".to_string()
+}
+
+fn to_va(a: AccountId) -> ValidAccountId {
+    ValidAccountId::try_from(a).unwrap()
+}
+
+/// test-token is built with a different version of near-sdk, so it's called via raw JSON.
+fn token_call(user: &UserAccount, method: &str, args: Value, deposit: u128) -> ExecutionResult {
+    user.call(
+        dai(),
+        method,
+        args.to_string().as_bytes(),
+        DEFAULT_GAS,
+        deposit,
+    )
+}
+
+fn token_balance(user: &UserAccount, account_id: &AccountId) -> u128 {
+    user.view(
+        dai(),
+        "ft_balance_of",
+        json!({ "account_id": account_id }).to_string().as_bytes(),
+    )
+    .unwrap_json::<U128>()
+    .0
+}
+
+fn near_balance(user: &UserAccount) -> u128 {
+    user.account().unwrap().amount
+}
+
+fn reserves(exchange: &ContractAccount<Exchange>) -> (u128, u128) {
+    let reserves = view!(exchange.get_reserves()).unwrap_json::<Reserves>();
+    (reserves.near_amount.0, reserves.token_amount.0)
+}
+
+/// Deploys token and exchange, registers exchange on the token and
+/// adds 5 NEAR and 10 tokens of liquidity from root.
+fn setup_exchange() -> (UserAccount, ContractAccount<Exchange>) {
+    let root = init_simulator(None);
+    root.deploy(&TEST_TOKEN_WASM_BYTES, dai(), to_yocto("100"));
+    token_call(&root, "new", json!({}), 0).assert_success();
+    token_call(
+        &root,
+        "mint",
+        json!({ "account_id": root.account_id, "amount": U128(to_yocto("1000")) }),
+        0,
+    )
+    .assert_success();
+    token_call(
+        &root,
+        "ar_register",
+        json!({ "account_id": exchange() }),
+        to_yocto("1"),
+    )
+    .assert_success();
+
+    let pool = deploy!(
+        contract: Exchange,
+        contract_id: exchange(),
+        bytes: &exchange_wasm_bytes(),
+        signer_account: root
+    );
+    call!(root, pool.new(to_va(dai()), 3, None)).assert_success();
+    call!(root, pool.storage_deposit(None), deposit = to_yocto("1")).assert_success();
+    call!(root, pool.add_liquidity(), deposit = to_yocto("5")).assert_success();
+    token_call(
+        &root,
+        "ft_transfer_call",
+        json!({
+            "receiver_id": exchange(),
+            "amount": U128(to_yocto("10")),
+            "msg": r#"{"v1": {"action": "add_liquidity", "min_shares": "0"}}"#,
+        }),
+        1,
+    )
+    .assert_success();
+    (root, pool)
+}
+
+/// Creates user with 100 NEAR that is registered on the token.
+fn create_trader(root: &UserAccount, account_id: &str) -> UserAccount {
+    let user = root.create_user(account_id.to_string(), to_yocto("100"));
+    token_call(
+        &user,
+        "ar_register",
+        json!({ "account_id": user.account_id }),
+        to_yocto("1"),
+    )
+    .assert_success();
+    user
+}
+
+#[test]
+fn test_liquidity() {
+    let (root, pool) = setup_exchange();
+    assert_eq!(reserves(&pool), (to_yocto("5"), to_yocto("10")));
+    assert_eq!(
+        view!(pool.shares_balance(to_va(root.account_id.clone()))).unwrap_json::<U128>(),
+        U128(to_yocto("5"))
+    );
+    assert_eq!(token_balance(&root, &root.account_id), to_yocto("990"));
+
+    // Adding 1 NEAR requires 2 tokens, the rest is refunded.
+    call!(root, pool.add_liquidity(), deposit = to_yocto("1")).assert_success();
+    token_call(
+        &root,
+        "ft_transfer_call",
+        json!({
+            "receiver_id": exchange(),
+            "amount": U128(to_yocto("3")),
+            "msg": r#"{"v1": {"action": "add_liquidity", "min_shares": "0"}}"#,
+        }),
+        1,
+    )
+    .assert_success();
+    assert_eq!(reserves(&pool), (to_yocto("6"), to_yocto("12") + 1));
+    assert_eq!(token_balance(&root, &root.account_id), to_yocto("988") - 1);
+
+    // Remove all liquidity, tokens and NEAR are returned.
+    let near_before = near_balance(&root);
+    call!(
+        root,
+        pool.remove_liquidity(U128(to_yocto("6")), U128(1), U128(1))
+    )
+    .assert_success();
+    assert_eq!(reserves(&pool), (0, 0));
+    assert_eq!(token_balance(&root, &root.account_id), to_yocto("1000"));
+    assert!(near_balance(&root) > near_before + to_yocto("5.9"));
+}
+
+#[test]
+fn test_swaps() {
+    let (root, pool) = setup_exchange();
+    let alice = create_trader(&root, "alice");
+
+    // NEAR to token.
+    call!(
+        alice,
+        pool.swap_near_to_token(1, None),
+        deposit = to_yocto("1")
+    )
+    .assert_success();
+    let (near_amount, token_amount) = reserves(&pool);
+    assert_eq!(near_amount, to_yocto("6"));
+    let tokens_bought = to_yocto("10") - token_amount;
+    assert!(tokens_bought > to_yocto("1.6"));
+    assert_eq!(token_balance(&root, &alice.account_id), tokens_bought);
+
+    // Token to NEAR, sells back all bought tokens.
+    let near_before = near_balance(&alice);
+    token_call(
+        &alice,
+        "ft_transfer_call",
+        json!({
+            "receiver_id": exchange(),
+            "amount": U128(tokens_bought),
+            "msg": r#"{"v1": {"action": "swap", "min_near_out": "1"}}"#,
+        }),
+        1,
+    )
+    .assert_success();
+    let (near_amount, token_amount) = reserves(&pool);
+    assert_eq!(token_amount, to_yocto("10"));
+    assert_eq!(token_balance(&root, &alice.account_id), 0);
+    let near_sold = to_yocto("6") - near_amount;
+    // Both swaps paid fees, so less than 1 NEAR comes back.
+    assert!(near_sold < to_yocto("1") && near_sold > to_yocto("0.99"));
+    assert!(near_balance(&alice) > near_before + near_sold - to_yocto("0.1"));
+}
+
+#[test]
+fn test_swap_slippage() {
+    let (root, pool) = setup_exchange();
+    let alice = create_trader(&root, "alice");
+
+    // Can't get 2 tokens for 1 NEAR.
+    let result = call!(
+        alice,
+        pool.swap_near_to_token(to_yocto("2"), None),
+        deposit = to_yocto("1")
+    );
+    assert!(!result.is_ok());
+    assert_eq!(reserves(&pool), (to_yocto("5"), to_yocto("10")));
+    assert!(near_balance(&alice) > to_yocto("98.9"));
+
+    // Can't get 1 NEAR for 1 token, tokens are returned by the token contract.
+    token_call(
+        &root,
+        "ft_transfer",
+        json!({ "receiver_id": alice.account_id, "amount": U128(to_yocto("1")) }),
+        1,
+    )
+    .assert_success();
+    token_call(
+        &alice,
+        "ft_transfer_call",
+        json!({
+            "receiver_id": exchange(),
+            "amount": U128(to_yocto("1")),
+            "msg": format!(r#"{{"v1": {{"action": "swap", "min_near_out": "{}"}}}}"#, to_yocto("1")),
+        }),
+        1,
+    );
+    assert_eq!(reserves(&pool), (to_yocto("5"), to_yocto("10")));
+    assert_eq!(token_balance(&root, &alice.account_id), to_yocto("1"));
+}
+
+#[test]
+fn test_unregistered_receiver() {
+    let (root, pool) = setup_exchange();
+    // Bob is not registered on the token, so can't receive bought tokens.
+    let bob = root.create_user("bob".to_string(), to_yocto("100"));
+    call!(
+        bob,
+        pool.swap_near_to_token(1, None),
+        deposit = to_yocto("1")
+    );
+    assert_eq!(reserves(&pool), (to_yocto("5"), to_yocto("10")));
+    assert_eq!(token_balance(&root, &bob.account_id), 0);
+    // Attached NEAR is refunded, only gas is spent.
+    assert!(near_balance(&bob) > to_yocto("99.9"));
+
+    // Bob holds shares but can't receive tokens on removing liquidity, shares are restored.
+    call!(bob, pool.storage_deposit(None), deposit = to_yocto("1")).assert_success();
+    call!(
+        root,
+        pool.ft_transfer(to_va(bob.account_id.clone()), U128(to_yocto("1")), None),
+        deposit = 1
+    )
+    .assert_success();
+    call!(
+        bob,
+        pool.remove_liquidity(U128(to_yocto("1")), U128(1), U128(1))
+    );
+    assert_eq!(reserves(&pool), (to_yocto("5"), to_yocto("10")));
+    assert_eq!(
+        view!(pool.shares_balance(to_va(bob.account_id.clone()))).unwrap_json::<U128>(),
+        U128(to_yocto("1"))
+    );
+}